@@ -4,49 +4,201 @@ use ring::aead::{Aad, BoundKey, Nonce, NonceSequence};
 use ring::error::Unspecified;
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod payload;
+pub use payload::open_payload;
 
 #[allow(non_snake_case)]
 pub mod android {
     use jni::JNIEnv;
-    use jni::objects::JClass;
+    use jni::objects::{JByteArray, JClass, JString};
+    use jni::sys::{jboolean, jint, jstring};
     use super::*;
 
     #[unsafe(no_mangle)]
     #[allow(clippy::missing_safety_doc)]
-    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_call(
-        _: JNIEnv,
+    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_answer(
+        mut env: JNIEnv,
+        _: JClass,
+        addr: JString,
+        id: jint,
+        key: JByteArray,
+    ) {
+        let result =
+            device(&mut env, &addr, id, &key).and_then(|(addr, id, key)| answer(&addr, id, &key));
+        throw_on_err(&mut env, result);
+    }
+
+    #[unsafe(no_mangle)]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_deny(
+        mut env: JNIEnv,
         _: JClass,
+        addr: JString,
+        id: jint,
+        key: JByteArray,
+        fraud: jboolean,
     ) {
-        call().unwrap();
+        let result = device(&mut env, &addr, id, &key)
+            .and_then(|(addr, id, key)| deny(&addr, id, &key, fraud != 0));
+        throw_on_err(&mut env, result);
+    }
+
+    #[unsafe(no_mangle)]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_rotateToken(
+        mut env: JNIEnv,
+        _: JClass,
+        addr: JString,
+        id: jint,
+        key: JByteArray,
+        token: JString,
+    ) {
+        let result = device(&mut env, &addr, id, &key).and_then(|(addr, id, key)| {
+            let token: String = env.get_string(&token)?.into();
+            rotate_token(&addr, id, &key, &token)
+        });
+        throw_on_err(&mut env, result);
+    }
+
+    #[unsafe(no_mangle)]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_sessions(
+        mut env: JNIEnv,
+        _: JClass,
+        addr: JString,
+        id: jint,
+        key: JByteArray,
+    ) -> jstring {
+        let result =
+            device(&mut env, &addr, id, &key).and_then(|(addr, id, key)| sessions(&addr, id, &key));
+        into_jstring(&mut env, result)
+    }
+
+    #[unsafe(no_mangle)]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe extern "C" fn Java_com_anunknownalias_persephone_core_crypto_Except_openPayload(
+        mut env: JNIEnv,
+        _: JClass,
+        key: JByteArray,
+        kid: JString,
+        nonce: JString,
+        payload: JString,
+    ) -> jstring {
+        let result = (|| {
+            let key = env.convert_byte_array(&key)?;
+            let kid: String = env.get_string(&kid)?.into();
+            let nonce: String = env.get_string(&nonce)?.into();
+            let payload: String = env.get_string(&payload)?.into();
+            open_payload(&key, &kid, &nonce, &payload)
+        })();
+        into_jstring(&mut env, result)
+    }
+
+    // the daemon address, device id and device key every signed call takes
+    fn device(
+        env: &mut JNIEnv,
+        addr: &JString,
+        id: jint,
+        key: &JByteArray,
+    ) -> Result<(String, u8, Vec<u8>), Box<dyn Error>> {
+        let addr: String = env.get_string(addr)?.into();
+        Ok((addr, id.try_into()?, env.convert_byte_array(key)?))
+    }
+
+    // failures surface as an IOException on the java side
+    fn throw_on_err(env: &mut JNIEnv, result: Result<(), Box<dyn Error>>) {
+        if let Err(e) = result {
+            let _ = env.throw_new("java/io/IOException", e.to_string());
+        }
+    }
+
+    // null with a pending IOException on failure
+    fn into_jstring(env: &mut JNIEnv, result: Result<String, Box<dyn Error>>) -> jstring {
+        match result.and_then(|s| Ok(env.new_string(s)?)) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_on_err(env, Err(e));
+                std::ptr::null_mut()
+            }
+        }
     }
 }
 
 const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
-const CHALLENGE_REQUESTED: u8 = 80;
 const CHALLENGE_ACCEPTED: u8 = 82;
 const CHALLENGE_APPROVED: u8 = 65;
-// const CHALLENGE_REJECTED: u8 = 83;
+const CHALLENGE_REJECTED: u8 = 83;
+const CHALLENGE_DENIED: u8 = 68;
+const CHALLENGE_FRAUD: u8 = 70;
 // const CHALLENGE_CANCELLED: u8 = 127;
+const CHALLENGE_CLAIM: u8 = 67;
+const TOKEN_ROTATE: u8 = 84;
+const TOKEN_ROTATED: u8 = 65;
+const SESSIONS_LIST: u8 = 76;
+const SESSIONS_LISTED: u8 = 65;
 const EOF: &[u8] = &[0; 4];
 const FUNC1: fn(u8, u8) -> u8 = |op: u8, x: u8| x.wrapping_mul(op);
 
-pub fn call() -> Result<(), Box<dyn Error>> {
-    let mut stream = TcpStream::connect("192.168.2.106:6667")?;
-    stream.write_all(&[CHALLENGE_REQUESTED])?;
+// answers a wake up as device `id`, so the daemon knows who approved
+pub fn answer(addr: &str, id: u8, key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut stream = claim(addr, id, key)?;
+    challenge(&mut stream)
+}
+
+// refuses a wake up as device `id`, `fraud` when the user never tried to log in
+pub fn deny(addr: &str, id: u8, key: &[u8], fraud: bool) -> Result<(), Box<dyn Error>> {
+    let mut stream = claim(addr, id, key)?;
+    read_challenge(&mut stream)?;
+    let reply = if fraud {
+        CHALLENGE_FRAUD
+    } else {
+        CHALLENGE_DENIED
+    };
+    stream.write_all(&[reply])?;
+    Ok(())
+}
+
+fn claim(addr: &str, id: u8, key: &[u8]) -> Result<TcpStream, Box<dyn Error>> {
+    signed(addr, CHALLENGE_CLAIM, id, key)
+}
+
+// sends [kind][id][timestamp u64][HMAC-SHA256 of everything before it]
+fn signed(addr: &str, kind: u8, id: u8, key: &[u8]) -> Result<TcpStream, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut msg = vec![kind, id];
+    msg.extend(timestamp.to_be_bytes());
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    msg.extend(ring::hmac::sign(&key, &msg).as_ref());
 
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&msg)?;
+    Ok(stream)
+}
+
+fn read_challenge(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut challenge_buf = [0; 32];
     let mut length = 0;
     let mut reading = true;
     while reading {
-        length += stream.read(&mut challenge_buf)?;
+        let n = stream.read(&mut challenge_buf[length..])?;
+        if n == 0 || challenge_buf[..length + n] == [CHALLENGE_REJECTED] {
+            return Err("challenge rejected, another device answered".into());
+        }
+        length += n;
         if challenge_buf[..length].ends_with(EOF) {
             length -= EOF.len();
             reading = false;
         }
     }
+    Ok(challenge_buf[..length].to_vec())
+}
+
+fn challenge(stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    let payload = read_challenge(stream)?;
     stream.write_all(&[CHALLENGE_ACCEPTED])?;
 
-    let payload = &challenge_buf[..length];
     let _id = payload[0];
     let op = payload[1];
     let mut data = payload[2..].to_vec();
@@ -75,6 +227,44 @@ pub fn call() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// `token` is the fcm token, UnifiedPush endpoint or WebPush subscription json
+pub fn rotate_token(addr: &str, id: u8, key: &[u8], token: &str) -> Result<(), Box<dyn Error>> {
+    let length: u16 = token.len().try_into()?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut msg = vec![TOKEN_ROTATE, id];
+    msg.extend(timestamp.to_be_bytes());
+    msg.extend(length.to_be_bytes());
+    msg.extend(token.as_bytes());
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    msg.extend(ring::hmac::sign(&key, &msg).as_ref());
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&msg)?;
+
+    let mut response = [0; 1];
+    stream.read_exact(&mut response)?;
+    match response[0] {
+        TOKEN_ROTATED => Ok(()),
+        _ => Err("token rotation rejected".into()),
+    }
+}
+
+// the json array of open sessions of the user the device is enrolled for
+pub fn sessions(addr: &str, id: u8, key: &[u8]) -> Result<String, Box<dyn Error>> {
+    let mut stream = signed(addr, SESSIONS_LIST, id, key)?;
+
+    let mut response = [0; 1];
+    stream.read_exact(&mut response)?;
+    if response[0] != SESSIONS_LISTED {
+        return Err("session listing rejected".into());
+    }
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let mut list = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut list)?;
+    Ok(String::from_utf8(list)?)
+}
+
 struct NonceGenerator {
     counter: [u8; 12],
 }
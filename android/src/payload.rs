@@ -0,0 +1,45 @@
+//! Opening of the sealed wake up payload, kept free of jni so the daemon's
+//! tests can check it against Device::seal
+
+use std::error::Error;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use ring::aead::{Aad, Nonce};
+
+const PAYLOAD_KEY_INFO: &[u8] = b"except push payload";
+
+// opens the kid, nonce and payload fields of a wake up push, see Device::seal
+pub fn open_payload(
+    key: &[u8],
+    kid: &str,
+    nonce: &str,
+    payload: &str,
+) -> Result<String, Box<dyn Error>> {
+    let expected: String = ring::digest::digest(&ring::digest::SHA256, key).as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if kid != expected {
+        return Err("payload sealed to a different key".into());
+    }
+
+    let mut payload_key = [0; 32];
+    ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &[])
+        .extract(key)
+        .expand(&[PAYLOAD_KEY_INFO], &ring::aead::AES_256_GCM)
+        .and_then(|okm| okm.fill(&mut payload_key))
+        .map_err(|_| "failed to derive payload key")?;
+    let payload_key = ring::aead::LessSafeKey::new(
+        ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, &payload_key)
+            .map_err(|_| "invalid payload key")?,
+    );
+
+    let nonce =
+        Nonce::try_assume_unique_for_key(&BASE64URL.decode(nonce)?).map_err(|_| "invalid nonce")?;
+    let mut data = BASE64URL.decode(payload)?;
+    let plaintext = payload_key
+        .open_in_place(nonce, Aad::from(kid.as_bytes()), &mut data)
+        .map_err(|_| "failed to open payload")?;
+    Ok(String::from_utf8(plaintext.to_vec())?)
+}
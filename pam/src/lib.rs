@@ -1,20 +1,25 @@
 use std::{
-    ffi::{c_char, c_int},
+    ffi::{c_char, c_int, c_void, CStr},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
     time::{Duration, Instant},
 };
 
 use log::{debug, error, trace, warn, LevelFilter};
 use syslog::{BasicLogger, Facility, Formatter3164};
 
-use except::ExceptManagerProxyBlocking;
+use except::{ExceptError, ExceptManagerProxyBlocking};
 
-// TODO: handle signals
+mod rules;
+use rules::{Cidr, Rules};
 
 fn send_msg<'a>(
     pamh: *const pam_sys::pam_handle_t,
     msg: &str,
     style: i32,
-) -> Result<&'a str, Box<dyn std::error::Error>> {
+) -> Result<Option<&'a str>, Box<dyn std::error::Error>> {
     let mut pam_conv_ptr: *const libc::c_void = std::ptr::null();
     let ret = unsafe { pam_sys::pam_get_item(pamh, pam_sys::PAM_CONV, &mut pam_conv_ptr) };
     if ret != pam_sys::PAM_SUCCESS || pam_conv_ptr.is_null() {
@@ -36,18 +41,38 @@ fn send_msg<'a>(
         resp_retcode: 0,
     };
     let pam_resp_ptr = &mut (&mut pam_resp as *mut pam_sys::pam_response);
-    if let Some(conv) = pam_conv.conv {
-        unsafe { conv(1, pam_msg_ptr, pam_resp_ptr, pam_conv.appdata_ptr) };
+    let Some(conv) = pam_conv.conv else {
+        return Err("The pam conv has no conversation function".into());
+    };
+    let ret = unsafe { conv(1, pam_msg_ptr, pam_resp_ptr, pam_conv.appdata_ptr) };
+    if ret != pam_sys::PAM_SUCCESS {
+        return Err(format!("The conversation failed: {}", ret).into());
     }
 
-    if pam_resp.resp.is_null() {
-        return Err("Response is null".into());
+    // informational messages usually get no response
+    if (*pam_resp_ptr).is_null() || unsafe { (**pam_resp_ptr).resp }.is_null() {
+        return Ok(None);
     }
 
     let resp = unsafe { **pam_resp_ptr };
     let resp_msg = unsafe { std::ffi::CStr::from_ptr(resp.resp).to_str()? };
 
-    Ok(resp_msg)
+    Ok(Some(resp_msg))
+}
+
+fn get_user(pamh: *mut pam_sys::pam_handle_t) -> Result<String, c_int> {
+    let mut user: *const c_char = std::ptr::null();
+    let ret = unsafe { pam_sys::pam_get_user(pamh, &mut user, std::ptr::null()) };
+    if ret != pam_sys::PAM_SUCCESS {
+        return Err(ret);
+    }
+    if user.is_null() {
+        return Err(pam_sys::PAM_USER_UNKNOWN);
+    }
+    match unsafe { std::ffi::CStr::from_ptr(user) }.to_str() {
+        Ok(user) if !user.is_empty() => Ok(user.into()),
+        _ => Err(pam_sys::PAM_USER_UNKNOWN),
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -58,53 +83,121 @@ pub extern "C" fn pam_sm_authenticate(
     argc: c_int,
     argv: *const *const c_char,
 ) -> c_int {
-    let mut ret = pam_sys::PAM_AUTH_ERR;
     if let Some(e) = logger().err() {
         error!("Failed to initialize logger: {}", e);
-        return ret;
+        return pam_sys::PAM_AUTH_ERR;
     };
 
-    let args: Vec<Args> = parse_agrs(argc, argv).unwrap_or_default();
-    if args.contains(&Args::Debug) {
+    let options = parse_args(argc, argv);
+    if options.debug {
         log::set_max_level(LevelFilter::Debug);
     }
 
-    let msg = "Please login using your registered device...";
-    let resp = match send_msg(pamh, msg, pam_sys::PAM_TEXT_INFO) {
-        Ok(resp) => resp,
-        Err(e) => &e.to_string(),
+    let user = match get_user(pamh) {
+        Ok(user) => user,
+        Err(ret) => {
+            error!("Failed to get the pam user: {ret}");
+            return ret;
+        }
     };
-    debug!("Login message response {:?}", resp);
-
-    debug!("Starting pam_sm_authenticate with: {:?}", args);
-    let connection = zbus::blocking::Connection::session().unwrap();
-    let excpet_proxy = ExceptManagerProxyBlocking::new(&connection).unwrap();
-
-    let max_tries = 3;
-    let now = std::time::Instant::now();
-    let timeout = Duration::from_secs(10);
-    for _ in 0..max_tries {
-        // TODO: handle multiple devices
-        debug!("Getting default device");
-        let id = match excpet_proxy.get_default_device() {
-            Ok(id) => id,
-            Err(e) => {
-                debug!("Failed to get devices: {e}");
-                break;
+
+    if !enforced(pamh, &options.rules) {
+        return pam_sys::PAM_IGNORE;
+    }
+
+    debug!("Starting pam_sm_authenticate for {user} with: {options:?}");
+    let excpet_proxy = match connect(options.bus) {
+        Ok(proxy) => proxy,
+        Err(e) => return options.on_unavailable.code(&e),
+    };
+
+    let signals = SignalGuard::install();
+    // the daemon gives up on the devices no later than the module does
+    let timeout = options.timeout.as_millis() as u64;
+    // left as is only when every try timed out
+    let mut ret = pam_sys::PAM_MAXTRIES;
+    for attempt in 0..options.max_tries {
+        // every try gets the full timeout, for the device to connect and answer
+        let deadline = Instant::now() + options.timeout;
+
+        // the daemon only answers once a device connected, wait for it off
+        // thread so signals are still noticed
+        let (tx, rx) = mpsc::channel();
+        let proxy = excpet_proxy.clone();
+        let (user, device) = (user.clone(), options.device);
+        std::thread::spawn(move || {
+            let started = match device {
+                Some(id) => {
+                    debug!("Calling start_verify for {id}");
+                    proxy.start_verify(user, id, timeout)
+                }
+                None => {
+                    debug!("Calling start_verify_all");
+                    proxy.start_verify_all(user, timeout)
+                }
+            };
+            let _ = tx.send(started);
+        });
+
+        if attempt == 0 {
+            match send_msg(pamh, &options.prompt, pam_sys::PAM_TEXT_INFO) {
+                Ok(resp) => debug!("Login message response {:?}", resp),
+                Err(e) => {
+                    error!("Failed to send the login message: {e}");
+                    cancel(&excpet_proxy);
+                    return pam_sys::PAM_CONV_ERR;
+                }
             }
-        };
+        }
 
-        debug!("Calling start_verify");
-        if let Err(e) = excpet_proxy.start_verify(id) {
+        let started = loop {
+            if signals.interrupted(&excpet_proxy) {
+                return pam_sys::PAM_ABORT;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                // stopped here rather than trusting the daemon's timeout, the
+                // cancelled call returns before the next try starts one
+                cancel(&excpet_proxy);
+                let _ = rx.recv_timeout(options.poll_interval);
+                break Err(ExceptError::Timeout("no device connected in time".into()));
+            }
+            match rx.recv_timeout(remaining.min(options.poll_interval)) {
+                Ok(started) => break started,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(ExceptError::ZBus(zbus::Error::Failure(
+                        "start verify call was lost".into(),
+                    )));
+                }
+            }
+        };
+        if let Err(e) = started {
             debug!("Failed to start verify: {e}");
+            ret = match e {
+                // no device connected back in time, try again
+                ExceptError::Timeout(_) => continue,
+                // the user has no devices at all
+                ExceptError::NotEnrolled(_) if !options.nullok => pam_sys::PAM_AUTHINFO_UNAVAIL,
+                // the daemon isn't running or went away mid call
+                ExceptError::ZBus(e) if unavailable(&e) => options.on_unavailable.code(&e),
+                e => pam_code(&e),
+            };
             break;
         }
 
         loop {
+            if signals.interrupted(&excpet_proxy) {
+                return pam_sys::PAM_ABORT;
+            }
+
             debug!("Checking verify status");
             match excpet_proxy.verify_status() {
                 Ok(true) => {
-                    debug!("Verify status is true");
+                    debug!(
+                        "Verify status is true, answered by {:?}",
+                        excpet_proxy.answered_by()
+                    );
                     ret = pam_sys::PAM_SUCCESS;
                     break;
                 }
@@ -113,28 +206,342 @@ pub extern "C" fn pam_sm_authenticate(
                 }
                 Err(e) => {
                     debug!("Failed to get verify status: {e}");
+                    ret = match e {
+                        ExceptError::ZBus(e) if unavailable(&e) => options.on_unavailable.code(&e),
+                        e => pam_code(&e),
+                    };
                     break;
                 }
             }
 
-            if Instant::now() - now > timeout {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
                 warn!("Timeout waiting for verify");
                 break;
             }
 
-            std::thread::sleep(Duration::from_millis(200));
+            std::thread::sleep(remaining.min(options.poll_interval));
         }
 
+        cancel(&excpet_proxy);
+
         if ret == pam_sys::PAM_SUCCESS {
+            // remembered for the session hooks
+            if let Ok(id) = excpet_proxy.answered_by() {
+                set_data(pamh, DEVICE_DATA, id);
+            }
+        }
+        if ret != pam_sys::PAM_MAXTRIES {
             break;
         }
     }
 
-    if let Err(e) = excpet_proxy.stop_verify() {
+    if ret == pam_sys::PAM_MAXTRIES {
+        warn!("No device approved within {} tries", options.max_tries);
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // pamh
+pub extern "C" fn pam_sm_setcred(
+    _pamh: *mut pam_sys::pam_handle_t,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    // there are no credentials to establish
+    pam_sys::PAM_SUCCESS
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // pamh
+pub extern "C" fn pam_sm_acct_mgmt(
+    pamh: *mut pam_sys::pam_handle_t,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    if let Some(e) = logger().err() {
+        error!("Failed to initialize logger: {}", e);
+        return pam_sys::PAM_AUTHINFO_UNAVAIL;
+    };
+
+    let options = parse_args(argc, argv);
+    if options.debug {
+        log::set_max_level(LevelFilter::Debug);
+    }
+
+    let user = match get_user(pamh) {
+        Ok(user) => user,
+        Err(ret) => {
+            error!("Failed to get the pam user: {ret}");
+            return ret;
+        }
+    };
+
+    if !enforced(pamh, &options.rules) {
+        return pam_sys::PAM_IGNORE;
+    }
+
+    debug!("Starting pam_sm_acct_mgmt for {user}");
+    let proxy = match connect(options.bus) {
+        Ok(proxy) => proxy,
+        Err(e) => return options.on_unavailable.code(&e),
+    };
+
+    match proxy.check_account(user) {
+        Ok(()) => pam_sys::PAM_SUCCESS,
+        Err(ExceptError::ZBus(e)) if unavailable(&e) => options.on_unavailable.code(&e),
+        Err(ExceptError::NotEnrolled(_)) if options.nullok => pam_sys::PAM_USER_UNKNOWN,
+        Err(ExceptError::NotEnrolled(_)) => pam_sys::PAM_AUTHINFO_UNAVAIL,
+        Err(e) => {
+            warn!("Account check failed: {e}");
+            pam_code(&e)
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // pamh
+pub extern "C" fn pam_sm_open_session(
+    pamh: *mut pam_sys::pam_handle_t,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    if logger().is_err() {
+        return pam_sys::PAM_IGNORE;
+    }
+
+    let options = parse_args(argc, argv);
+    if options.debug {
+        log::set_max_level(LevelFilter::Debug);
+    }
+
+    let user = match get_user(pamh) {
+        Ok(user) => user,
+        Err(ret) => {
+            error!("Failed to get the pam user: {ret}");
+            return pam_sys::PAM_IGNORE;
+        }
+    };
+    let service = get_item_str(pamh, pam_sys::PAM_SERVICE).unwrap_or_default();
+    let tty = get_item_str(pamh, pam_sys::PAM_TTY).unwrap_or_default();
+    let rhost = get_item_str(pamh, pam_sys::PAM_RHOST).unwrap_or_default();
+    // 0 when the session was not authenticated by except
+    let device = get_data::<u8>(pamh, DEVICE_DATA).unwrap_or_default();
+
+    debug!("Opening session for {user} on {service}");
+    // sessions are bookkeeping only, never block a login over them
+    let opened = connect(options.bus)
+        .map_err(ExceptError::from)
+        .and_then(|proxy| proxy.open_session(user, service, tty, rhost, device));
+    match opened {
+        Ok(id) => {
+            set_data(pamh, SESSION_DATA, id);
+            pam_sys::PAM_SUCCESS
+        }
+        Err(e) => {
+            warn!("Failed to open session: {e}");
+            pam_sys::PAM_IGNORE
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::not_unsafe_ptr_arg_deref)] // pamh
+pub extern "C" fn pam_sm_close_session(
+    pamh: *mut pam_sys::pam_handle_t,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    if logger().is_err() {
+        return pam_sys::PAM_IGNORE;
+    }
+
+    let options = parse_args(argc, argv);
+    if options.debug {
+        log::set_max_level(LevelFilter::Debug);
+    }
+
+    let Some(id) = get_data::<u32>(pamh, SESSION_DATA) else {
+        debug!("No except session to close");
+        return pam_sys::PAM_IGNORE;
+    };
+
+    debug!("Closing session {id}");
+    let closed = connect(options.bus)
+        .map_err(ExceptError::from)
+        .and_then(|proxy| proxy.close_session(id));
+    match closed {
+        Ok(()) => pam_sys::PAM_SUCCESS,
+        Err(e) => {
+            warn!("Failed to close session {id}: {e}");
+            pam_sys::PAM_IGNORE
+        }
+    }
+}
+
+// stops this module's request on the daemon, dismissing it on the phone
+fn cancel(proxy: &ExceptManagerProxyBlocking) {
+    if let Err(e) = proxy.cancel_verify() {
         error!("Failed to stop verify: {e}");
     }
+}
 
-    ret
+// the last signal caught by `on_signal`, 0 for none
+static CAUGHT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: c_int) {
+    CAUGHT_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Catches interrupts while a request is pending, the application's own
+/// handlers are restored and a caught signal is raised again on drop
+struct SignalGuard {
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl SignalGuard {
+    const SIGNALS: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    fn install() -> Self {
+        CAUGHT_SIGNAL.store(0, Ordering::SeqCst);
+        let mut previous = Vec::new();
+        for signal in Self::SIGNALS {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            if unsafe { libc::sigaction(signal, &action, &mut old) } != 0 {
+                warn!("Failed to catch signal {signal}");
+                continue;
+            }
+            // the application doesn't want to hear about it, neither do we
+            if old.sa_sigaction == libc::SIG_IGN {
+                unsafe { libc::sigaction(signal, &old, std::ptr::null_mut()) };
+                continue;
+            }
+            previous.push((signal, old));
+        }
+        Self { previous }
+    }
+
+    // cancels the request when a signal came in
+    fn interrupted(&self, proxy: &ExceptManagerProxyBlocking) -> bool {
+        let signal = CAUGHT_SIGNAL.load(Ordering::SeqCst);
+        if signal == 0 {
+            return false;
+        }
+        warn!("Interrupted by signal {signal}, cancelling verify");
+        cancel(proxy);
+        true
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for (signal, old) in &self.previous {
+            unsafe { libc::sigaction(*signal, old, std::ptr::null_mut()) };
+        }
+        let signal = CAUGHT_SIGNAL.swap(0, Ordering::SeqCst);
+        if signal != 0 {
+            unsafe { libc::raise(signal) };
+        }
+    }
+}
+
+fn connect(bus: Bus) -> Result<ExceptManagerProxyBlocking<'static>, zbus::Error> {
+    let connection = match bus {
+        Bus::Session => zbus::blocking::Connection::session(),
+        Bus::System => zbus::blocking::Connection::system(),
+    }?;
+    ExceptManagerProxyBlocking::new(&connection)
+}
+
+// whether the rules want this login approved on a device
+fn enforced(pamh: *const pam_sys::pam_handle_t, rules: &Rules) -> bool {
+    let service = get_item_str(pamh, pam_sys::PAM_SERVICE).unwrap_or_default();
+    let tty = get_item_str(pamh, pam_sys::PAM_TTY).unwrap_or_default();
+    let rhost = get_item_str(pamh, pam_sys::PAM_RHOST).unwrap_or_default();
+    rules.enforce(&service, &tty, &rhost)
+}
+
+fn get_item_str(pamh: *const pam_sys::pam_handle_t, item: c_int) -> Option<String> {
+    let mut value: *const c_void = std::ptr::null();
+    let ret = unsafe { pam_sys::pam_get_item(pamh, item, &mut value) };
+    if ret != pam_sys::PAM_SUCCESS || value.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(value as *const c_char) }
+        .to_str()
+        .ok()
+        .map(String::from)
+}
+
+// keys for the data passed between the hooks of one pam handle
+const DEVICE_DATA: &CStr = c"except_device";
+const SESSION_DATA: &CStr = c"except_session";
+
+unsafe extern "C" fn cleanup<T>(_: *mut pam_sys::pam_handle_t, data: *mut c_void, _: c_int) {
+    drop(unsafe { Box::from_raw(data as *mut T) });
+}
+
+fn set_data<T>(pamh: *mut pam_sys::pam_handle_t, name: &CStr, value: T) {
+    let data = Box::into_raw(Box::new(value)) as *mut c_void;
+    let ret = unsafe { pam_sys::pam_set_data(pamh, name.as_ptr(), data, Some(cleanup::<T>)) };
+    if ret != pam_sys::PAM_SUCCESS {
+        warn!("Failed to set pam data {name:?}: {ret}");
+        drop(unsafe { Box::from_raw(data as *mut T) });
+    }
+}
+
+fn get_data<T: Copy>(pamh: *const pam_sys::pam_handle_t, name: &CStr) -> Option<T> {
+    let mut data: *const c_void = std::ptr::null();
+    let ret = unsafe { pam_sys::pam_get_data(pamh, name.as_ptr(), &mut data) };
+    if ret != pam_sys::PAM_SUCCESS || data.is_null() {
+        return None;
+    }
+    Some(unsafe { *(data as *const T) })
+}
+
+// whether the bus says nobody is there to answer, any other error came from
+// a daemon that is up and goes through pam_code
+fn unavailable(e: &zbus::Error) -> bool {
+    const UNAVAILABLE: [&str; 3] = [
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        "org.freedesktop.DBus.Error.NoReply",
+        "org.freedesktop.DBus.Error.Disconnected",
+    ];
+    match e {
+        zbus::Error::MethodError(name, _, _) => UNAVAILABLE.contains(&name.as_str()),
+        zbus::Error::FDO(e) => matches!(
+            **e,
+            zbus::fdo::Error::ServiceUnknown(_)
+                | zbus::fdo::Error::NoReply(_)
+                | zbus::fdo::Error::Disconnected(_)
+        ),
+        _ => false,
+    }
+}
+
+fn pam_code(e: &ExceptError) -> c_int {
+    match e {
+        ExceptError::NoDevice(_)
+        | ExceptError::PushFailed(_)
+        | ExceptError::EnrollFailed(_)
+        | ExceptError::ZBus(_) => pam_sys::PAM_AUTHINFO_UNAVAIL,
+        ExceptError::NotEnrolled(_) => pam_sys::PAM_USER_UNKNOWN,
+        // denied on the phone
+        ExceptError::PermissionDenied(_) => pam_sys::PAM_PERM_DENIED,
+        // reported on the phone, the whole stack should stop
+        ExceptError::Fraud(_) => pam_sys::PAM_ABORT,
+        ExceptError::Timeout(_) => pam_sys::PAM_MAXTRIES,
+        // another login holds the daemon, never a code that may be ignored
+        ExceptError::Cancelled(_) | ExceptError::Busy(_) => pam_sys::PAM_AUTH_ERR,
+    }
 }
 
 fn logger() -> Result<(), Box<dyn std::error::Error>> {
@@ -151,50 +558,139 @@ fn logger() -> Result<(), Box<dyn std::error::Error>> {
         Ok(logger) => logger,
     };
 
-    log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(LevelFilter::Info))?;
+    // every hook of the stack calls this, only the first one installs it
+    if log::set_boxed_logger(Box::new(BasicLogger::new(logger))).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
 
     Ok(())
 }
 
-enum Args {
-    Debug,
-    UnknownArg(String),
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bus {
+    Session,
+    System,
+}
+
+// what authentication returns when the daemon can't be reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnUnavailable {
+    Deny,
+    Ignore,
+    Fallback,
+}
+
+impl OnUnavailable {
+    fn code(self, e: &dyn std::fmt::Display) -> c_int {
+        let (code, policy, path) = match self {
+            OnUnavailable::Deny => (pam_sys::PAM_AUTH_ERR, "deny", "denying access"),
+            OnUnavailable::Ignore => (pam_sys::PAM_IGNORE, "ignore", "ignoring this module"),
+            OnUnavailable::Fallback => (
+                pam_sys::PAM_AUTHINFO_UNAVAIL,
+                "fallback",
+                "leaving it to the rest of the stack",
+            ),
+        };
+        error!("The except daemon is unavailable ({e}), {path} as on_unavailable={policy}");
+        code
+    }
 }
 
-impl std::fmt::Debug for Args {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Args::Debug => write!(f, "Debug"),
-            Args::UnknownArg(s) => write!(f, "UnknownArg({})", s),
+#[derive(Debug)]
+struct Options {
+    debug: bool,
+    // for all tries together
+    timeout: Duration,
+    max_tries: u32,
+    poll_interval: Duration,
+    // challenge only this device instead of all of them
+    device: Option<u8>,
+    prompt: String,
+    bus: Bus,
+    // users without devices get PAM_USER_UNKNOWN instead of
+    // PAM_AUTHINFO_UNAVAIL, so the stack can skip the module for them
+    nullok: bool,
+    on_unavailable: OnUnavailable,
+    // when approval is required at all
+    rules: Rules,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            debug: false,
+            timeout: Duration::from_secs(10),
+            max_tries: 3,
+            poll_interval: Duration::from_millis(200),
+            device: None,
+            prompt: "Please login using your registered device...".into(),
+            bus: Bus::Session,
+            nullok: false,
+            on_unavailable: OnUnavailable::Deny,
+            rules: Rules::default(),
         }
     }
 }
 
-impl std::cmp::PartialEq for Args {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Args::Debug, Args::Debug) => true,
-            (Args::UnknownArg(a), Args::UnknownArg(b)) => a == b,
-            _ => false,
+impl Options {
+    fn parse_arg(&mut self, arg: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg, None),
+        };
+        match (key, value) {
+            ("debug", None) => self.debug = true,
+            ("nullok", None) => self.nullok = true,
+            ("timeout", Some(v)) => self.timeout = Duration::from_secs(v.parse()?),
+            ("max_tries", Some(v)) => self.max_tries = v.parse()?,
+            ("poll_interval", Some(v)) => self.poll_interval = Duration::from_millis(v.parse()?),
+            ("device", Some(v)) => self.device = Some(v.parse()?),
+            ("prompt", Some(v)) => self.prompt = v.into(),
+            ("bus", Some("session")) => self.bus = Bus::Session,
+            ("bus", Some("system")) => self.bus = Bus::System,
+            ("on_unavailable", Some("deny")) => self.on_unavailable = OnUnavailable::Deny,
+            ("on_unavailable", Some("ignore")) => self.on_unavailable = OnUnavailable::Ignore,
+            ("on_unavailable", Some("fallback")) => self.on_unavailable = OnUnavailable::Fallback,
+            ("services", Some(v)) => self.rules.services = list(v).map(String::from).collect(),
+            ("skip_services", Some(v)) => {
+                self.rules.skip_services = list(v).map(String::from).collect()
+            }
+            ("skip_ttys", Some(v)) => self.rules.skip_ttys = list(v).map(String::from).collect(),
+            ("remote_only", None) => self.rules.remote_only = true,
+            ("networks", Some(v)) => self.rules.networks = cidrs(v)?,
+            ("skip_networks", Some(v)) => self.rules.skip_networks = cidrs(v)?,
+            _ => return Err("unknown argument".into()),
         }
+        Ok(())
     }
 }
 
-fn parse_agrs(
-    argc: c_int,
-    argv: *const *const c_char,
-) -> Result<Vec<Args>, Box<dyn std::error::Error>> {
-    let mut args: Vec<Args> = vec![];
+// comma separated argument values
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn cidrs(value: &str) -> Result<Vec<Cidr>, Box<dyn std::error::Error>> {
+    list(value).map(str::parse).collect()
+}
+
+// invalid and unknown arguments are logged and otherwise ignored
+fn parse_args(argc: c_int, argv: *const *const c_char) -> Options {
+    let mut options = Options::default();
     for i in 0..argc as isize {
         let arg = unsafe { *argv.offset(i) };
         let c_str = unsafe { std::ffi::CStr::from_ptr(arg) };
-        let arg = match c_str.to_str()? {
-            "debug" => Args::Debug,
-            s => Args::UnknownArg(s.into()),
+        let arg = match c_str.to_str() {
+            Ok(arg) => arg,
+            Err(e) => {
+                warn!("Ignoring non utf-8 argument: {e}");
+                continue;
+            }
         };
-        args.push(arg);
+        if let Err(e) = options.parse_arg(arg) {
+            warn!("Ignoring argument {arg:?}: {e}");
+        }
     }
 
-    Ok(args)
+    options
 }
@@ -0,0 +1,202 @@
+use std::{error::Error, net::IpAddr, str::FromStr};
+
+use log::debug;
+
+/// Decides from the pam items whether a login needs approval at all, an
+/// empty rule set enforces everywhere
+#[derive(Debug, Default)]
+pub(crate) struct Rules {
+    // only these services, all when empty
+    pub(crate) services: Vec<String>,
+    pub(crate) skip_services: Vec<String>,
+    // exact names or prefixes ending in `*`
+    pub(crate) skip_ttys: Vec<String>,
+    // skip logins without a remote host
+    pub(crate) remote_only: bool,
+    // only remote hosts in these, all when empty
+    pub(crate) networks: Vec<Cidr>,
+    pub(crate) skip_networks: Vec<Cidr>,
+}
+
+impl Rules {
+    pub(crate) fn enforce(&self, service: &str, tty: &str, rhost: &str) -> bool {
+        debug!("Evaluating rules for service {service:?}, tty {tty:?}, rhost {rhost:?}");
+        if !self.services.is_empty() && !self.services.iter().any(|s| s == service) {
+            debug!(
+                "Skipping, service {service:?} is not one of {:?}",
+                self.services
+            );
+            return false;
+        }
+        if self.skip_services.iter().any(|s| s == service) {
+            debug!("Skipping, service {service:?} is in skip_services");
+            return false;
+        }
+
+        let tty = tty.strip_prefix("/dev/").unwrap_or(tty);
+        if let Some(pattern) = self.skip_ttys.iter().find(|p| tty_matches(p, tty)) {
+            debug!("Skipping, tty {tty:?} matches {pattern:?}");
+            return false;
+        }
+
+        let addr = parse_host(rhost);
+        let local =
+            rhost.is_empty() || rhost == "localhost" || addr.is_some_and(|a| a.is_loopback());
+        if self.remote_only && local {
+            debug!("Skipping, {rhost:?} is not a remote host");
+            return false;
+        }
+        if let Some(net) = addr.and_then(|a| self.skip_networks.iter().find(|n| n.contains(a))) {
+            debug!("Skipping, {rhost:?} is in {net}");
+            return false;
+        }
+        if !self.networks.is_empty() {
+            match addr {
+                Some(a) if !self.networks.iter().any(|n| n.contains(a)) => {
+                    debug!("Skipping, {rhost:?} is in none of the networks");
+                    return false;
+                }
+                Some(_) => (),
+                None if local => {
+                    debug!("Skipping, a local login is in none of the networks");
+                    return false;
+                }
+                // a name could resolve to anything, so it is not let through
+                None => debug!("Enforcing, host name {rhost:?} can't be matched to a network"),
+            }
+        }
+
+        debug!("Rules require approval");
+        true
+    }
+}
+
+fn tty_matches(pattern: &str, tty: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => tty.starts_with(prefix),
+        None => pattern == tty,
+    }
+}
+
+// host names are left alone, only addresses are matched against networks
+fn parse_host(rhost: &str) -> Option<IpAddr> {
+    let rhost = rhost.trim_start_matches('[').trim_end_matches(']');
+    match rhost.parse().ok()? {
+        IpAddr::V6(addr) => Some(addr.to_ipv4_mapped().map_or(IpAddr::V6(addr), IpAddr::V4)),
+        addr => Some(addr),
+    }
+}
+
+/// A network in CIDR notation, a bare address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (net, addr, bits) = match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                (u32::from(net).into(), u32::from(addr).into(), 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => (u128::from(net), u128::from(addr), 128),
+            _ => return false,
+        };
+        // a shift by all the bits leaves nothing to compare
+        let shift = bits - self.prefix;
+        net.checked_shr(shift) == addr.checked_shr(shift)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()?;
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse()?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(format!("{addr} has only {bits} bits").into());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks(networks: &[&str]) -> Rules {
+        Rules {
+            networks: networks.iter().map(|n| n.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn prefix_zero_contains_everything() {
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("10.1.2.3".parse().unwrap()));
+        assert!(any.contains("255.255.255.255".parse().unwrap()));
+        assert!(!any.contains("::1".parse().unwrap()));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn prefix_full_contains_one_address() {
+        let one: Cidr = "192.0.2.7/32".parse().unwrap();
+        assert!(one.contains("192.0.2.7".parse().unwrap()));
+        assert!(!one.contains("192.0.2.8".parse().unwrap()));
+        assert_eq!(one, "192.0.2.7".parse().unwrap());
+        assert!("192.0.2.7/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn v4_mapped_v6_matches_v4_networks() {
+        let rules = networks(&["192.0.2.0/24"]);
+        assert!(rules.enforce("sshd", "ssh", "::ffff:192.0.2.10"));
+        assert!(rules.enforce("sshd", "ssh", "[::ffff:192.0.2.10]"));
+        assert!(!rules.enforce("sshd", "ssh", "::ffff:198.51.100.1"));
+    }
+
+    #[test]
+    fn host_name_is_enforced_with_networks() {
+        let rules = networks(&["192.0.2.0/24"]);
+        assert!(rules.enforce("sshd", "ssh", "attacker.example.com"));
+        assert!(!rules.enforce("sshd", "ssh", "198.51.100.1"));
+        assert!(!rules.enforce("login", "tty1", ""));
+    }
+
+    #[test]
+    fn remote_only_skips_local_logins() {
+        let rules = Rules {
+            remote_only: true,
+            ..Default::default()
+        };
+        assert!(!rules.enforce("login", "tty1", ""));
+        assert!(!rules.enforce("sshd", "ssh", "localhost"));
+        assert!(!rules.enforce("sshd", "ssh", "127.0.0.1"));
+        assert!(!rules.enforce("sshd", "ssh", "::1"));
+        assert!(rules.enforce("sshd", "ssh", "192.0.2.10"));
+        assert!(rules.enforce("sshd", "ssh", "host.example.com"));
+    }
+}
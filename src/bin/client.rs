@@ -1,9 +1,77 @@
-use except::pam_client;
+use except::{BusType, Config, DEFAULT_CONFIG, ExceptManagerProxy, pam_client};
 
 #[tokio::main]
 async fn main() {
-    match pam_client() {
-        Ok(_) => (),
-        Err(e) => println!("Error: {:?}", e),
+    let res = match std::env::args().nth(1).as_deref() {
+        Some("health") => health().await,
+        Some("enroll") => enroll().await,
+        Some("revoke") => revoke().await,
+        Some("sessions") => sessions().await,
+        _ => pam_client(),
+    };
+    if let Err(e) = res {
+        println!("Error: {:?}", e);
     }
 }
+
+async fn health() -> Result<(), Box<dyn std::error::Error>> {
+    let connection = connect().await?;
+    let proxy = ExceptManagerProxy::new(&connection).await?;
+    let report = proxy.get_health().await?;
+    print!("{}", report);
+    Ok(())
+}
+
+async fn enroll() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(2);
+    let usage = "usage: client enroll <name> <user> <notifier> [address]";
+    let name = args.next().ok_or(usage)?;
+    let user = args.next().ok_or(usage)?;
+    let notifier = args.next().ok_or(usage)?;
+    let address = args.next().unwrap_or_default();
+
+    let connection = connect().await?;
+    let proxy = ExceptManagerProxy::new(&connection).await?;
+    let (id, key) = proxy
+        .enroll_device(name.clone(), user.clone(), notifier, address)
+        .await?;
+    println!("enrolled {} as device {} for {}", name, id, user);
+    println!("device key: {}", key);
+    Ok(())
+}
+
+async fn revoke() -> Result<(), Box<dyn std::error::Error>> {
+    let usage = "usage: client revoke <id>";
+    let id: u8 = std::env::args().nth(2).ok_or(usage)?.parse()?;
+
+    let connection = connect().await?;
+    let proxy = ExceptManagerProxy::new(&connection).await?;
+    proxy.revoke_device(id).await?;
+    println!("revoked device {}", id);
+    Ok(())
+}
+
+async fn sessions() -> Result<(), Box<dyn std::error::Error>> {
+    let usage = "usage: client sessions <user>";
+    let user = std::env::args().nth(2).ok_or(usage)?;
+
+    let connection = connect().await?;
+    let proxy = ExceptManagerProxy::new(&connection).await?;
+    for s in proxy.list_sessions(user).await? {
+        println!(
+            "{:<4} {} {} tty={} rhost={} device={} started={}",
+            s.id, s.user, s.service, s.tty, s.rhost, s.device, s.started
+        );
+    }
+    Ok(())
+}
+
+// the bus the daemon registered on, read from the same config it loads
+async fn connect() -> Result<zbus::Connection, Box<dyn std::error::Error>> {
+    let path = std::env::var("EXCEPT_CONFIG").unwrap_or(DEFAULT_CONFIG.into());
+    let connection = match Config::load(&path)?.bus {
+        BusType::Session => zbus::Connection::session().await?,
+        BusType::System => zbus::Connection::system().await?,
+    };
+    Ok(connection)
+}
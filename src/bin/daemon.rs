@@ -3,7 +3,21 @@ use tracing::error;
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let mut except = except::Except::new("0.0.0.0", 6667);
+    let path = std::env::var("EXCEPT_CONFIG").unwrap_or(except::DEFAULT_CONFIG.into());
+    let config = match except::Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Error: {:?}", e);
+            std::process::exit(1)
+        }
+    };
+    let mut except = match except::Except::new(config) {
+        Ok(except) => except,
+        Err(e) => {
+            error!("Error: {:?}", e);
+            std::process::exit(1)
+        }
+    };
     if let Err(e) = except.dbus_connect().await {
         error!("Error: {:?}", e);
         std::process::exit(1)
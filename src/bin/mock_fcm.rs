@@ -0,0 +1,391 @@
+//! Stand-in for the google OAuth token endpoint and fcm `messages:send`,
+//! point `token_uri` of a test service account and `fcm.base_url` at it.
+//!
+//! usage: mock_fcm <address> <public key pem>
+//!
+//! POST /mock/errors   queue responses for the next sends, e.g.
+//!                     {"status": "UNREGISTERED"}, {"status": "QUOTA_EXCEEDED",
+//!                     "retry_after": 1, "times": 2} or {"http": 503}
+//! GET  /mock/messages every message received so far
+//! POST /mock/reset    forgets messages, tokens and queued errors
+
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+const MAX_REQUEST: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+struct InjectedError {
+    // an fcm error code, answered with the http status fcm uses for it
+    status: Option<String>,
+    // a bare http status with a body that isn't an fcm error
+    http: Option<u16>,
+    retry_after: Option<u64>,
+    #[serde(default = "one")]
+    times: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+#[derive(Default)]
+struct State {
+    tokens: HashSet<String>,
+    messages: Vec<Value>,
+    errors: VecDeque<InjectedError>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/json".into())],
+            body: body.to_string(),
+        }
+    }
+}
+
+struct Mock {
+    key: DecodingKey,
+    state: Mutex<State>,
+}
+
+impl Mock {
+    fn handle(&self, req: &Request) -> Response {
+        match (req.method.as_str(), req.path.as_str()) {
+            ("POST", "/token") => self.token(req),
+            ("POST", path) if path.ends_with("/messages:send") => self.send(req),
+            ("POST", "/mock/errors") => self.queue_errors(req),
+            ("GET", "/mock/messages") => {
+                Response::json(200, json!(self.state.lock().unwrap().messages))
+            }
+            ("POST", "/mock/reset") => {
+                *self.state.lock().unwrap() = State::default();
+                Response::json(200, json!({}))
+            }
+            _ => Response::json(404, json!({"error": "not found"})),
+        }
+    }
+
+    // RFC 7523, the assertion has to be signed by the test key
+    fn token(&self, req: &Request) -> Response {
+        let body = String::from_utf8_lossy(&req.body);
+        if form_value(&body, "grant_type").as_deref() != Some(JWT_BEARER_GRANT) {
+            return oauth_error("unsupported_grant_type", "expected a jwt-bearer grant");
+        }
+        let Some(assertion) = form_value(&body, "assertion") else {
+            return oauth_error("invalid_request", "no assertion");
+        };
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.validate_aud = false;
+        let claims = match jsonwebtoken::decode::<Value>(&assertion, &self.key, &validation) {
+            Ok(data) => data.claims,
+            Err(e) => return oauth_error("invalid_grant", &format!("bad assertion: {}", e)),
+        };
+        if claims["scope"] != FCM_SCOPE {
+            return oauth_error("invalid_scope", "expected the firebase.messaging scope");
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let access_token = format!("mock-token-{}", state.tokens.len() + 1);
+        state.tokens.insert(access_token.clone());
+        info!(iss = claims["iss"].as_str(), "issued {}", access_token);
+        Response::json(
+            200,
+            json!({
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            }),
+        )
+    }
+
+    fn send(&self, req: &Request) -> Response {
+        let mut state = self.state.lock().unwrap();
+        let token = req
+            .header("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !token.is_some_and(|t| state.tokens.contains(t)) {
+            return fcm_error(401, "UNAUTHENTICATED", None);
+        }
+        let msg: Value = match serde_json::from_slice(&req.body) {
+            Ok(msg) => msg,
+            Err(e) => return fcm_error(400, "INVALID_ARGUMENT", Some(&e.to_string())),
+        };
+
+        if let Some(injected) = state.errors.front_mut() {
+            let injected_error = injected.clone();
+            injected.times -= 1;
+            if injected.times == 0 {
+                state.errors.pop_front();
+            }
+            warn!(?injected_error, "answering with an injected error");
+            return injected_response(&injected_error);
+        }
+
+        let name = format!(
+            "{}/messages/{}",
+            project(&req.path),
+            state.messages.len() + 1
+        );
+        info!(name, "received {}", msg);
+        state.messages.push(msg);
+        Response::json(200, json!({ "name": name }))
+    }
+
+    fn queue_errors(&self, req: &Request) -> Response {
+        // a single error or a list of them
+        let errors = match serde_json::from_slice(&req.body) {
+            Ok(Value::Array(errors)) => serde_json::from_value(Value::Array(errors)),
+            Ok(error) => serde_json::from_value(error).map(|e| vec![e]),
+            Err(e) => Err(e),
+        };
+        let errors: Vec<InjectedError> = match errors {
+            Ok(errors) => errors,
+            Err(e) => return Response::json(400, json!({"error": e.to_string()})),
+        };
+        let mut state = self.state.lock().unwrap();
+        state
+            .errors
+            .extend(errors.into_iter().filter(|e| e.times > 0));
+        Response::json(200, json!({ "queued": state.errors.len() }))
+    }
+}
+
+fn injected_response(injected: &InjectedError) -> Response {
+    let mut res = match (&injected.status, injected.http) {
+        (Some(status), _) => {
+            let code = match status.as_str() {
+                "INVALID_ARGUMENT" => 400,
+                "THIRD_PARTY_AUTH_ERROR" => 401,
+                "SENDER_ID_MISMATCH" => 403,
+                "UNREGISTERED" => 404,
+                "QUOTA_EXCEEDED" => 429,
+                "UNAVAILABLE" => 503,
+                _ => 500,
+            };
+            fcm_error(code, status, None)
+        }
+        // what a proxy or load balancer in front of fcm would answer
+        (None, Some(code)) => Response {
+            status: code,
+            headers: vec![("Content-Type", "text/html".into())],
+            body: format!("<html><body>{}</body></html>", code),
+        },
+        (None, None) => fcm_error(500, "INTERNAL", None),
+    };
+    if let Some(secs) = injected.retry_after {
+        res.headers.push(("Retry-After", secs.to_string()));
+    }
+    res
+}
+
+// the canonical status goes in `status`, the fcm specific code in the details
+fn fcm_error(code: u16, error_code: &str, message: Option<&str>) -> Response {
+    let status = match code {
+        400 => "INVALID_ARGUMENT",
+        401 => "UNAUTHENTICATED",
+        403 => "PERMISSION_DENIED",
+        404 => "NOT_FOUND",
+        429 => "RESOURCE_EXHAUSTED",
+        503 => "UNAVAILABLE",
+        _ => "INTERNAL",
+    };
+    Response::json(
+        code,
+        json!({
+            "error": {
+                "code": code,
+                "message": message.unwrap_or(error_code),
+                "status": status,
+                "details": [{
+                    "@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                    "errorCode": error_code,
+                }],
+            }
+        }),
+    )
+}
+
+fn oauth_error(error: &str, description: &str) -> Response {
+    Response::json(
+        400,
+        json!({ "error": error, "error_description": description }),
+    )
+}
+
+fn project(path: &str) -> &str {
+    path.trim_start_matches("/v1/")
+        .trim_end_matches("/messages:send")
+}
+
+fn form_value(body: &str, key: &str) -> Option<String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| percent_decode(v))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => out.push(b' '),
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 2;
+            }
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Box<dyn Error>> {
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed".into());
+        }
+        buf.extend(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_REQUEST {
+            return Err("request too large".into());
+        }
+    };
+
+    let head = String::from_utf8(buf[..header_end].to_vec())?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().ok_or("empty request")?.split(' ');
+    let method = request_line.next().ok_or("no method")?.to_string();
+    let target = request_line.next().ok_or("no path")?;
+    let path = target.split('?').next().unwrap_or(target).to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let length: usize = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, v)| v.parse())
+        .transpose()?
+        .unwrap_or(0);
+    if length > MAX_REQUEST {
+        return Err("request too large".into());
+    }
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed".into());
+        }
+        body.extend(&chunk[..n]);
+    }
+    body.truncate(length);
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn handle(mut stream: TcpStream, mock: Arc<Mock>) -> Result<(), Box<dyn Error>> {
+    let req = read_request(&mut stream).await.map_err(|e| e.to_string())?;
+    let res = mock.handle(&req);
+    info!(req.method, req.path, res.status, "handled request");
+
+    let mut out = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        res.status,
+        res.body.len()
+    );
+    for (name, value) in &res.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("\r\n");
+    out.push_str(&res.body);
+    stream.write_all(out.as_bytes()).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    if let Err(e) = run().await {
+        error!("Error: {:?}", e);
+        std::process::exit(1)
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: mock_fcm <address> <public key pem>";
+    let address = args.next().ok_or(usage)?;
+    let public_key = args.next().ok_or(usage)?;
+    let pem =
+        std::fs::read(&public_key).map_err(|e| format!("failed to read {}: {}", public_key, e))?;
+    let mock = Arc::new(Mock {
+        key: DecodingKey::from_rsa_pem(&pem)?,
+        state: Mutex::new(State::default()),
+    });
+
+    let listener = TcpListener::bind(&address).await?;
+    info!("mock fcm listening on http://{}", listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let mock = mock.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, mock).await {
+                error!("an error occurred; error = {:?}", e);
+            }
+        });
+    }
+}
@@ -7,18 +7,31 @@ use ring::{
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 pub const CHALLENGE_REQUESTED: u8 = 80;
 const CHALLENGE_ACCEPTED: u8 = 82;
 const CHALLENGE_APPROVED: u8 = 65;
-const CHALLENGE_REJECTED: u8 = 83;
+pub const CHALLENGE_REJECTED: u8 = 83;
 pub const CHALLENGE_CANCELLED: u8 = 127;
+// sent by the device in place of CHALLENGE_ACCEPTED
+const CHALLENGE_DENIED: u8 = 68;
+const CHALLENGE_FRAUD: u8 = 70;
 const EOF: &[u8] = &[0; 4];
 
 const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
 const FUNC1: fn(u8, u8) -> u8 = |op: u8, x: u8| x.wrapping_mul(op);
 
+/// How a device answered a challenge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Answer {
+    Approved,
+    // the response did not verify
+    Rejected,
+    Denied,
+    Fraud,
+}
+
 pub(crate) struct Challenge {
     id: u8,
     data: Vec<u8>,
@@ -108,7 +121,7 @@ impl Challenge {
         Ok((res_buf, length))
     }
 
-    pub async fn run(stream: &mut TcpStream, id: u8, peer: &str) -> Result<bool, Box<dyn Error>> {
+    pub async fn run(stream: &mut TcpStream, id: u8, peer: &str) -> Result<Answer, Box<dyn Error>> {
         debug!(peer, "generating and encrypting the challenge");
         let mut challenge = Challenge::with_fn(FUNC1, id);
 
@@ -121,6 +134,14 @@ impl Challenge {
         stream.read_exact(&mut accepted).await?;
         match accepted {
             [CHALLENGE_ACCEPTED] => info!(peer, "challenge has been accepted"),
+            [CHALLENGE_DENIED] => {
+                info!(peer, "challenge has been denied on the device");
+                return Ok(Answer::Denied);
+            }
+            [CHALLENGE_FRAUD] => {
+                warn!(peer, "request has been reported as fraud on the device");
+                return Ok(Answer::Fraud);
+            }
             _ => {
                 info!(peer, "challenge has been rejected");
                 return Err("challenge rejected".into());
@@ -135,59 +156,16 @@ impl Challenge {
         let result = match challenge.verify(plaintext).await {
             Ok(_) => {
                 debug!(peer, "challenge response verified, sending approval");
-                (&[CHALLENGE_APPROVED], true)
+                (&[CHALLENGE_APPROVED], Answer::Approved)
             }
             Err(e) => {
                 error!("{}", e);
-                (&[CHALLENGE_REJECTED], false)
+                (&[CHALLENGE_REJECTED], Answer::Rejected)
             }
         };
         stream.write_all(result.0).await?;
         Ok(result.1)
     }
-
-    // kept for reference
-    #[allow(dead_code)]
-    pub async fn call() -> Result<(), Box<dyn Error>> {
-        let mut stream = TcpStream::connect("192.168.2.106:6667").await?;
-        stream.write_all(&[CHALLENGE_REQUESTED]).await?;
-
-        let mut challenge_buf = [0; 32];
-        let mut length = 0;
-        let mut reading = true;
-        while reading {
-            length += stream.read(&mut challenge_buf).await?;
-            if challenge_buf[..length].ends_with(EOF) {
-                length -= EOF.len();
-                reading = false;
-            }
-        }
-        stream.write_all(&[CHALLENGE_ACCEPTED]).await?;
-
-        let mut challenge = Challenge::from(&challenge_buf[..length]);
-        challenge.decrypt_data().await?;
-
-        let response = Response::new(&challenge.data, challenge.op, FUNC1);
-        let mut response_data = response.data.clone();
-        let unbound_key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, KEY).unwrap();
-        let mut sealing_key = ring::aead::SealingKey::new(unbound_key, NONCE_GEN);
-        sealing_key
-            .seal_in_place_append_tag(Aad::empty(), &mut response_data)
-            .unwrap();
-        response_data.extend(EOF);
-        stream.write_all(&response_data).await?;
-
-        let mut response = [0; 1];
-        stream.read_exact(&mut response).await?;
-
-        if response[0] == CHALLENGE_APPROVED {
-            info!("Challenge approved by: {}", stream.peer_addr()?.ip());
-        } else {
-            info!("Challenge rejected");
-        }
-
-        Ok(())
-    }
 }
 
 impl From<&[u8]> for Challenge {
@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+pub const DEFAULT_CONFIG: &str = "config.json";
+const DEFAULT_STATE_DIR: &str = "/var/lib/except";
+const REGISTRY_NAME: &str = "devices.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    // must match the bus= argument of the pam module
+    pub bus: BusType,
+    // the enrolled devices and their keys, defaults to devices.json in
+    // $STATE_DIRECTORY or /var/lib/except
+    pub registry: Option<PathBuf>,
+    pub fcm: FcmConfig,
+    pub unifiedpush: UnifiedPushConfig,
+    pub webpush: WebPushConfig,
+    pub delivery: DeliveryConfig,
+    pub http: HttpConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".into(),
+            port: 6667,
+            bus: BusType::default(),
+            registry: None,
+            fcm: FcmConfig::default(),
+            unifiedpush: UnifiedPushConfig::default(),
+            webpush: WebPushConfig::default(),
+            delivery: DeliveryConfig::default(),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusType {
+    #[default]
+    Session,
+    System,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FcmConfig {
+    // service account key, otherwise GOOGLE_APPLICATION_CREDENTIALS or the
+    // systemd credentials directory are searched
+    pub credentials: Option<PathBuf>,
+    // fetch tokens from the GCE/GKE metadata server instead of a key file
+    pub metadata_server: bool,
+    // overrides the project_id of the service account, required for the
+    // metadata server and external accounts
+    pub project_id: Option<String>,
+    // overrides https://fcm.googleapis.com, mostly useful for pointing at a mock
+    pub base_url: Option<String>,
+    // dry run, fcm validates messages but never delivers them
+    pub validate_only: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnifiedPushConfig {
+    // sent as a bearer token, e.g. an ntfy access token
+    pub bearer_token: Option<String>,
+    // PEM certificate that replaces the system roots for push endpoints
+    pub pinned_cert: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebPushConfig {
+    // contact for the push service, a mailto: or https: url
+    pub subject: Option<String>,
+    // base64url encoded VAPID key pair, the private key is read from a file
+    pub public_key: Option<String>,
+    pub private_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStrategy {
+    // only the default device
+    Single,
+    // every device at once, the first approval wins
+    #[default]
+    Multicast,
+    // one device after another, each getting escalate_after seconds
+    Escalate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    pub strategy: DeliveryStrategy,
+    pub escalate_after: u64,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            strategy: DeliveryStrategy::default(),
+            escalate_after: 10,
+        }
+    }
+}
+
+/// The outbound client shared by the push and token requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    // seconds
+    pub connect_timeout: u64,
+    pub timeout: u64,
+    // e.g. http://proxy.corp:3128, otherwise HTTPS_PROXY and friends apply
+    pub proxy: Option<String>,
+    // PEM certificates trusted in addition to the system roots
+    pub extra_roots: Vec<PathBuf>,
+    pub user_agent: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10,
+            timeout: 30,
+            proxy: None,
+            extra_roots: vec![],
+            user_agent: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `f`, a missing file yields the defaults
+    pub fn load(f: &str) -> Result<Self, Box<dyn Error>> {
+        debug!("loading config from file: {}", f);
+        match std::fs::read_to_string(f) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no config file found, using defaults");
+                Ok(Config::default())
+            }
+            Err(e) => Err(format!("failed to read config {}: {}", f, e).into()),
+        }
+    }
+
+    /// Where the device registry lives, never relative to the working directory
+    pub fn registry_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let path = match &self.registry {
+            Some(path) => path.clone(),
+            None => std::env::var("STATE_DIRECTORY")
+                .ok()
+                .and_then(|dirs| dirs.split(':').next().map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+                .join(REGISTRY_NAME),
+        };
+        if !path.is_absolute() {
+            return Err(format!("registry path {:?} must be absolute", path).into());
+        }
+        Ok(path)
+    }
+}
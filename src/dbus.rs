@@ -1,72 +1,399 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
 use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, Ordering},
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use event_listener::Listener;
-use rand::prelude::*;
-use tokio::sync::broadcast::Sender;
-use tracing::debug;
-use zbus::interface;
+use futures_util::StreamExt;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+use zbus::message::Header;
+use zbus::zvariant::ObjectPath;
+use zbus::{Connection, interface};
 
-use crate::google::{Credentials, FCMMessage, send_message};
+use crate::challenge::Answer;
+use crate::config::{Config, DeliveryConfig, DeliveryStrategy};
+use crate::device::{Device, Registry};
+use crate::error::ExceptError;
+use crate::google::Credentials;
+use crate::health::{self, Health, HealthReport};
+use crate::http;
+use crate::notifier::{
+    FcmNotifier, Notifier, NotifierKind, NotifyError, Push, PushKind, UnifiedPushNotifier,
+    WebPushNotifier,
+};
+use crate::session::{Session, Sessions};
+
+// how long a woken device has to connect back, pushes expire along with it
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub(crate) struct ExceptManager {
     hostname: String,
-    active_id: Option<u8>,
+    active_id: Mutex<Option<u8>>,
+    // unique bus name of the client that started the current request
+    requester: Mutex<Option<String>>,
+    // stops the current request should its client leave the bus
+    watcher: Mutex<Option<JoinHandle<()>>>,
     active_id_verified: Arc<AtomicBool>,
     event: Arc<event_listener::Event>,
-    tx: Sender<u8>,
-    google_creds: Credentials,
+    // wakes a request waiting on its devices when it gets stopped
+    cancel: event_listener::Event,
+    // none when no usable service account was found, fcm is disabled then
+    google_creds: Option<Arc<Credentials>>,
+    notifiers: HashMap<NotifierKind, Arc<dyn Notifier>>,
+    // devices woken for the current request, dismissed once it ends
+    notified: Mutex<Vec<Device>>,
+    // random id of the current request, carried by its pushes
+    request: AtomicU64,
+    health: Arc<Health>,
+    registry: Arc<Mutex<Registry>>,
+    sessions: Arc<Mutex<Sessions>>,
+    delivery: DeliveryConfig,
+    last_push: AtomicU64,
 }
 
 impl ExceptManager {
     pub(crate) fn new(
         event: Arc<event_listener::Event>,
-        tx: Sender<u8>,
         verified: Arc<AtomicBool>,
-    ) -> Self {
+        health: Arc<Health>,
+        registry: Arc<Mutex<Registry>>,
+        sessions: Arc<Mutex<Sessions>>,
+        config: &Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let hostname = std::fs::read_to_string("/etc/hostname").unwrap();
         let hostname = hostname.trim().to_string();
         let active_id_verified = verified;
-        let google_creds = Credentials::from_service_account_file("except.json");
-        Self {
+        let client = http::client(&config.http)?;
+        let mut notifiers: HashMap<NotifierKind, Arc<dyn Notifier>> = HashMap::new();
+        let google_creds =
+            Credentials::from_config(&config.fcm, client.clone()).and_then(|creds| {
+                let creds = Arc::new(creds);
+                let notifier = FcmNotifier::new(client.clone(), creds.clone(), &config.fcm)?;
+                notifiers.insert(NotifierKind::Fcm, Arc::new(notifier));
+                Ok(creds)
+            });
+        let google_creds = match google_creds {
+            Ok(creds) => {
+                creds.spawn_refresh();
+                Some(creds)
+            }
+            Err(e) => {
+                error!("fcm notifier disabled: {}", e);
+                None
+            }
+        };
+        match UnifiedPushNotifier::new(client.clone(), &config.unifiedpush, &config.http) {
+            Ok(notifier) => {
+                notifiers.insert(NotifierKind::UnifiedPush, Arc::new(notifier));
+            }
+            Err(e) => error!("unifiedpush notifier disabled: {}", e),
+        }
+        match WebPushNotifier::new(client, &config.webpush) {
+            Ok(notifier) => {
+                notifiers.insert(NotifierKind::WebPush, Arc::new(notifier));
+            }
+            Err(e) => debug!("web push notifier disabled: {}", e),
+        }
+        Ok(Self {
             hostname,
-            active_id: None,
+            active_id: Mutex::new(None),
+            requester: Mutex::new(None),
+            watcher: Mutex::new(None),
             active_id_verified,
             event,
-            tx,
+            cancel: event_listener::Event::new(),
             google_creds,
+            notifiers,
+            notified: Mutex::new(vec![]),
+            request: AtomicU64::new(0),
+            health,
+            registry,
+            sessions,
+            delivery: config.delivery.clone(),
+            last_push: AtomicU64::new(0),
+        })
+    }
+
+    async fn send_auth_notification(&self, device: &Device) -> Result<(), NotifyError> {
+        let notifier = self.notifiers.get(&device.notifier).ok_or_else(|| {
+            NotifyError::NotConfigured(format!("notifier unavailable: {:?}", device.notifier))
+        })?;
+        let wake_up = Push {
+            kind: PushKind::WakeUp,
+            request: self.request.load(Ordering::Acquire),
+            id: device.id,
+            hostname: &self.hostname,
+            ttl: DEVICE_TIMEOUT,
+        };
+        // recorded ahead of the push so a quick device can't claim before it
+        self.health.device_woken(device.id);
+        notifier.notify(device, &wake_up).await?;
+        self.notified.lock().unwrap().push(device.clone());
+        self.last_push.store(health::now(), Ordering::Release);
+        Ok(())
+    }
+
+    async fn wake(&self, device: &Device) -> Result<(), ExceptError> {
+        debug!(id = device.id, notifier = ?device.notifier, "waking device");
+        match self.send_auth_notification(device).await {
+            Ok(()) => Ok(()),
+            Err(NotifyError::Gone(e)) => {
+                if let Err(e) = self.registry.lock().unwrap().mark_push_dead(device.id) {
+                    error!(id = device.id, "failed to mark push address as gone: {}", e);
+                }
+                Err(ExceptError::PushFailed(format!(
+                    "device push address is gone: {}",
+                    e
+                )))
+            }
+            Err(e) => Err(ExceptError::PushFailed(format!(
+                "failed to send auth notification: {}",
+                e
+            ))),
         }
     }
 
-    async fn firebase_send_auth_notification(
-        &mut self,
-        id: u8,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.google_creds.refresh().await?;
-        let message = auth_notification(id, &self.hostname)?;
-        let token = self.google_creds.get_access_token()?;
-        send_message(token, message).await
+    // wakes devices off `devices` until one push goes through
+    async fn wake_next(
+        &self,
+        devices: &mut impl Iterator<Item = Device>,
+    ) -> Result<u8, ExceptError> {
+        let mut result = Err(ExceptError::NoDevice("no device left to wake".into()));
+        for device in devices {
+            match self.wake(&device).await {
+                Ok(()) => return Ok(device.id),
+                Err(e) => {
+                    warn!(id = device.id, "failed to wake device: {}", e);
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Wakes `devices` and waits up to `timeout` for the first one to connect
+    /// back, with escalation the next device is woken every `escalate_after` seconds
+    async fn verify_devices(
+        &self,
+        connection: &Connection,
+        requester: &Header<'_>,
+        user: &str,
+        devices: Vec<Device>,
+        strategy: DeliveryStrategy,
+        timeout: Duration,
+    ) -> Result<(), ExceptError> {
+        // listening before the request is claimed so no stop can slip by
+        let mut cancelled = self.cancel.listen();
+        let sender = requester
+            .sender()
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+        {
+            let mut active = self.requester.lock().unwrap();
+            if active.is_some() {
+                return Err(ExceptError::Busy(format!(
+                    "auth flow already in progress for: {:?}",
+                    self.active_id.lock().unwrap()
+                )));
+            }
+            *active = Some(sender.clone());
+        }
+        if let Some(path) = requester.path() {
+            let watcher = Self::watch_requester(connection.clone(), path.to_owned(), sender);
+            *self.watcher.lock().unwrap() = Some(watcher);
+        }
+        self.request.store(rand::random(), Ordering::Release);
+        self.health.begin_request(user);
+        let res = self
+            .wait_for_devices(&mut cancelled, devices, strategy, timeout)
+            .await;
+        if res.is_err() {
+            self.reset();
+        }
+        res
+    }
+
+    async fn wait_for_devices(
+        &self,
+        cancelled: &mut event_listener::EventListener,
+        devices: Vec<Device>,
+        strategy: DeliveryStrategy,
+        timeout: Duration,
+    ) -> Result<(), ExceptError> {
+        // a late approval from an earlier request must not count for this one
+        self.active_id_verified.store(false, Ordering::Release);
+        self.health.set_answered_by(None);
+        self.health.set_refused_by(None);
+
+        let deadline = Instant::now() + timeout.min(DEVICE_TIMEOUT);
+        let mut listener = self.event.listen();
+        let mut devices = devices.into_iter();
+        let first = self.wake_next(&mut devices).await?;
+        match strategy {
+            DeliveryStrategy::Single => devices = Vec::new().into_iter(),
+            DeliveryStrategy::Multicast => {
+                for device in devices.by_ref() {
+                    if let Err(e) = self.wake(&device).await {
+                        warn!(id = device.id, "failed to wake device: {}", e);
+                    }
+                }
+            }
+            DeliveryStrategy::Escalate => (),
+        }
+        *self.active_id.lock().unwrap() = Some(first);
+        debug!(first, ?strategy, "started auth flow");
+
+        let escalate_after = Duration::from_secs(self.delivery.escalate_after);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = match devices.len() {
+                0 => remaining,
+                _ => remaining.min(escalate_after),
+            };
+            tokio::select! {
+                _ = &mut listener => break,
+                _ = &mut *cancelled => {
+                    return Err(ExceptError::Cancelled(format!(
+                        "auth flow stopped while waiting for: {}",
+                        first
+                    )));
+                }
+                _ = tokio::time::sleep(wait) => (),
+            }
+            if Instant::now() >= deadline {
+                return Err(ExceptError::Timeout(format!(
+                    "device did not connect for: {}",
+                    first
+                )));
+            }
+            listener = self.event.listen();
+            debug!("no device connected yet, escalating");
+            if let Err(e) = self.wake_next(&mut devices).await {
+                warn!("escalation failed: {}", e);
+            }
+        }
+
+        debug!(first, "device connected for verification");
+        Ok(())
+    }
+
+    // sent in the background, a device that misses it only keeps a stale
+    // notification around
+    fn dismiss_notifications(&self) {
+        let notified = std::mem::take(&mut *self.notified.lock().unwrap());
+        let request = self.request.load(Ordering::Acquire);
+        for device in notified {
+            let Some(notifier) = self.notifiers.get(&device.notifier).cloned() else {
+                continue;
+            };
+            let hostname = self.hostname.clone();
+            tokio::spawn(async move {
+                let dismiss = Push {
+                    kind: PushKind::Dismiss,
+                    request,
+                    id: device.id,
+                    hostname: &hostname,
+                    ttl: DEVICE_TIMEOUT,
+                };
+                match notifier.notify(&device, &dismiss).await {
+                    Ok(()) => debug!(id = device.id, "dismissed auth notification"),
+                    Err(e) => warn!(id = device.id, "failed to dismiss auth notification: {}", e),
+                }
+            });
+        }
     }
-}
 
-#[rustfmt::skip]
-fn auth_notification(id: u8, hostname: &str) -> Result<FCMMessage, Box<dyn std::error::Error>> {
-    format!(r#"
-{{
-    "message": {{
-        "token": "f3Aff7-AQ6Wid_UFfd15RQ:APA91bFtxN49M-Y_dhDKnG8m_0YBv7K9CGD-SBA6O0V8Ls75mYayRlGTC3vuRs9rU_D6R8bF-M0QMXifrcoR8xZ7fIETdgxmqFsQ_qzGIwNU83jCsclFOPs",
-        "android": {{
-            "priority": "HIGH",
-            "data": {{
-                "id": "{id}",
-                "device": "{hostname}"
-            }}
-        }}
-    }}
-}}"#).trim().try_into()
+    // a client that dies mid request never cancels it, its name leaving the
+    // bus stops the request instead
+    fn watch_requester(
+        connection: Connection,
+        path: ObjectPath<'static>,
+        requester: String,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let gone = async {
+                let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+                let mut changes = proxy
+                    .receive_name_owner_changed_with_args(&[(0, requester.as_str())])
+                    .await?;
+                // it may have left before the match rule was in place
+                if proxy.name_has_owner(requester.as_str().try_into()?).await? {
+                    while let Some(change) = changes.next().await {
+                        if change.args()?.new_owner().is_none() {
+                            break;
+                        }
+                    }
+                }
+                connection
+                    .object_server()
+                    .interface::<_, ExceptManager>(path)
+                    .await
+            };
+            let manager = match gone.await {
+                Ok(manager) => manager,
+                Err(e) => {
+                    warn!(requester, "failed to watch the requesting client: {}", e);
+                    return;
+                }
+            };
+            let manager = manager.get().await;
+            if manager.requester.lock().unwrap().as_ref() == Some(&requester) {
+                info!(requester, "requesting client left the bus, stopping");
+                manager.stop();
+            }
+        })
+    }
+
+    // ends the current request, whatever state it is in
+    fn reset(&self) {
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
+        self.health.end_request();
+        *self.active_id.lock().unwrap() = None;
+        *self.requester.lock().unwrap() = None;
+        self.dismiss_notifications();
+    }
+
+    // root may act for anyone, everyone else only for their own user, a
+    // device or session that belongs to nobody is left to root
+    async fn check_caller(
+        connection: &Connection,
+        header: &Header<'_>,
+        user: Option<&str>,
+    ) -> Result<(), ExceptError> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| ExceptError::PermissionDenied("call has no sender to check".into()))?;
+        let uid = zbus::fdo::DBusProxy::new(connection)
+            .await?
+            .get_connection_unix_user(sender.clone().into())
+            .await
+            .map_err(zbus::Error::from)?;
+        if uid == 0 {
+            return Ok(());
+        }
+        match (user, user_name(uid)) {
+            (Some(user), Some(name)) if user == name => Ok(()),
+            _ => Err(ExceptError::PermissionDenied(format!(
+                "uid {} may not act for: {}",
+                uid,
+                user.unwrap_or("nobody")
+            ))),
+        }
+    }
+
+    fn stop(&self) {
+        self.reset();
+        self.cancel.notify(usize::MAX);
+        let active_id_verified = false;
+        self.active_id_verified
+            .store(active_id_verified, Ordering::Release);
+        debug!(active_id_verified, "auth flow stopped and state reset to");
+    }
 }
 
 /*
@@ -89,39 +416,420 @@ fn auth_notification(id: u8, hostname: &str) -> Result<FCMMessage, Box<dyn std::
     )
 )]
 impl ExceptManager {
-    // TODO: enrollment
-    async fn get_default_device(&self) -> u8 {
-        let mut rng = rand::thread_rng();
-        rng.r#gen()
+    async fn enroll_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        name: String,
+        user: String,
+        notifier: String,
+        address: String,
+    ) -> Result<(u8, String), ExceptError> {
+        Self::check_caller(connection, &header, Some(&user)).await?;
+        let notifier: NotifierKind = notifier.parse().map_err(ExceptError::EnrollFailed)?;
+        let address = Some(address).filter(|a| !a.is_empty());
+        let mut registry = self.registry.lock().unwrap();
+        registry
+            .enroll(&name, &user, notifier, address)
+            .map(|device| (device.id, device.key.clone()))
+            .map_err(|e| ExceptError::EnrollFailed(format!("failed to enroll {}: {}", name, e)))
     }
 
-    async fn start_verify(&mut self, id: u8) -> String {
-        debug!(id, "starting Auth flow");
-        if let Err(e) = self.firebase_send_auth_notification(id).await {
-            return format!("failed to send auth notification: {}", e);
+    async fn get_default_device(&self, user: String) -> Result<u8, ExceptError> {
+        let registry = self.registry.lock().unwrap();
+        if !registry.has_devices(&user) {
+            return Err(ExceptError::NotEnrolled(format!(
+                "no devices enrolled for: {}",
+                user
+            )));
+        }
+        match registry.default_device(&user) {
+            Some(device) => Ok(device.id),
+            None => Err(ExceptError::NoDevice(
+                "no enrolled device with a push address".into(),
+            )),
         }
-        self.active_id = Some(id);
-        let listener = self.event.listen();
-        listener.wait_timeout(Duration::from_secs(30));
-        let _ = self.tx.send(id);
-        debug!(id, "sent id to challenge manager for verification");
-        format!("started auth flow for: {}", id)
     }
 
-    async fn verify_status(&self) -> bool {
-        let status = self.active_id_verified.load(Ordering::Acquire);
-        debug!(status, "status verification check");
-        status
+    /// Wakes device `id` of `user`, `timeout` is how many milliseconds the
+    /// caller waits for it to connect back
+    async fn start_verify(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        user: String,
+        id: u8,
+        timeout: u64,
+    ) -> Result<(), ExceptError> {
+        Self::check_caller(connection, &header, Some(&user)).await?;
+        let device = {
+            let registry = self.registry.lock().unwrap();
+            let device = registry
+                .get(id)
+                .ok_or_else(|| ExceptError::NotEnrolled(format!("unknown device: {}", id)))?;
+            if !device.belongs_to(&user) {
+                return Err(ExceptError::PermissionDenied(format!(
+                    "device {} is not enrolled for: {}",
+                    id, user
+                )));
+            }
+            if !device.reachable() {
+                return Err(ExceptError::PushFailed(format!(
+                    "device has no usable push address: {}",
+                    device.name
+                )));
+            }
+            device.clone()
+        };
+
+        self.verify_devices(
+            connection,
+            &header,
+            &user,
+            vec![device],
+            DeliveryStrategy::Single,
+            Duration::from_millis(timeout),
+        )
+        .await
     }
 
-    async fn stop_verify(&mut self) {
-        self.active_id = None;
-        let active_id_verified = false;
-        self.active_id_verified
-            .store(active_id_verified, Ordering::Release);
-        debug!(
-            active_id = self.active_id,
-            active_id_verified, "auth flow stopped and state reset to"
-        );
+    /// Wakes every reachable device of `user` according to the delivery
+    /// strategy, `timeout` as for start_verify
+    async fn start_verify_all(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        user: String,
+        timeout: u64,
+    ) -> Result<(), ExceptError> {
+        Self::check_caller(connection, &header, Some(&user)).await?;
+        let devices = {
+            let registry = self.registry.lock().unwrap();
+            if !registry.has_devices(&user) {
+                return Err(ExceptError::NotEnrolled(format!(
+                    "no devices enrolled for: {}",
+                    user
+                )));
+            }
+            registry.reachable_devices(&user)
+        };
+        if devices.is_empty() {
+            return Err(ExceptError::NoDevice(
+                "no enrolled device with a push address".into(),
+            ));
+        }
+        self.verify_devices(
+            connection,
+            &header,
+            &user,
+            devices,
+            self.delivery.strategy,
+            Duration::from_millis(timeout),
+        )
+        .await
+    }
+
+    /// True once a device approved, an error once one refused the request
+    async fn verify_status(&self) -> Result<bool, ExceptError> {
+        match self.health.refused_by() {
+            Some((id, Answer::Fraud)) => Err(ExceptError::Fraud(format!(
+                "request reported as fraudulent on device: {}",
+                id
+            ))),
+            Some((id, _)) => Err(ExceptError::PermissionDenied(format!(
+                "request denied on device: {}",
+                id
+            ))),
+            None => {
+                let status = self.active_id_verified.load(Ordering::Acquire);
+                debug!(status, "status verification check");
+                Ok(status)
+            }
+        }
+    }
+
+    async fn get_health(&self) -> HealthReport {
+        let devices_last_seen = self.health.devices_last_seen();
+        HealthReport {
+            listener_address: self.health.listener_address(),
+            listener_state: self.health.listener_state(),
+            bus_type: self.health.bus_type(),
+            fcm_token_valid: self.fcm_token_valid().await,
+            fcm_token_expiry: self.fcm_token_expiry().await,
+            last_push: self.last_push().await,
+            pending_requests: self.pending_requests().await,
+            enrolled_devices: self.registry.lock().unwrap().len() as u32,
+            devices_last_seen,
+        }
+    }
+
+    // the properties are read off shared state and never signal a change,
+    // which also keeps proxies from caching them
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn listener_address(&self) -> String {
+        self.health.listener_address()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn listener_state(&self) -> String {
+        self.health.listener_state()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn bus_type(&self) -> String {
+        self.health.bus_type()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn fcm_token_valid(&self) -> bool {
+        self.google_creds
+            .as_ref()
+            .is_some_and(|creds| creds.token_valid())
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn fcm_token_expiry(&self) -> u64 {
+        self.google_creds
+            .as_ref()
+            .and_then(|creds| creds.token_expires_at())
+            .map(health::unix_secs)
+            .unwrap_or_default()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn last_push(&self) -> u64 {
+        self.last_push.load(Ordering::Acquire)
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn pending_requests(&self) -> u32 {
+        self.active_id.lock().unwrap().is_some() as u32
+    }
+
+    // 0 until a device approved the current request
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn answered_by(&self) -> u8 {
+        self.health.answered_by().unwrap_or_default()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn devices_last_seen(&self) -> HashMap<u8, u64> {
+        self.health.devices_last_seen()
+    }
+
+    /// Stops the current request, but only for the client that started it
+    async fn cancel_verify(&self, #[zbus(header)] header: Header<'_>) {
+        let sender = header.sender().map(|s| s.to_string());
+        if *self.requester.lock().unwrap() != sender {
+            debug!(
+                ?sender,
+                "not cancelling an auth flow started by another client"
+            );
+            return;
+        }
+        self.stop();
+    }
+
+    async fn revoke_device(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        id: u8,
+    ) -> Result<(), ExceptError> {
+        let owner = self
+            .registry
+            .lock()
+            .unwrap()
+            .get(id)
+            .ok_or_else(|| ExceptError::NotEnrolled(format!("unknown device: {}", id)))?
+            .user
+            .clone();
+        Self::check_caller(connection, &header, owner.as_deref()).await?;
+        self.registry
+            .lock()
+            .unwrap()
+            .revoke(id)
+            .map_err(|e| ExceptError::NotEnrolled(format!("failed to revoke {}: {}", id, e)))
+    }
+
+    /// Succeeds when `user` has at least one enrolled device that isn't revoked
+    async fn check_account(&self, user: String) -> Result<(), ExceptError> {
+        let registry = self.registry.lock().unwrap();
+        if !registry.has_devices(&user) {
+            return Err(ExceptError::NotEnrolled(format!(
+                "no devices enrolled for: {}",
+                user
+            )));
+        }
+        if !registry.has_active_devices(&user) {
+            return Err(ExceptError::PermissionDenied(format!(
+                "all devices of {} are revoked",
+                user
+            )));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn open_session(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        user: String,
+        service: String,
+        tty: String,
+        rhost: String,
+        device: u8,
+    ) -> Result<u32, ExceptError> {
+        Self::check_caller(connection, &header, Some(&user)).await?;
+        let id =
+            self.sessions
+                .lock()
+                .unwrap()
+                .open(user.clone(), service.clone(), tty, rhost, device);
+        info!(id, user, service, device, "session opened");
+        Ok(id)
+    }
+
+    async fn close_session(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        id: u32,
+    ) -> Result<(), ExceptError> {
+        let owner = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|session| session.user.clone());
+        let Some(owner) = owner else {
+            debug!(id, "closing unknown session");
+            return Ok(());
+        };
+        Self::check_caller(connection, &header, Some(&owner)).await?;
+        if let Some(session) = self.sessions.lock().unwrap().close(id) {
+            info!(id, session.user, "session closed");
+        }
+        Ok(())
+    }
+
+    async fn list_sessions(
+        &self,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(header)] header: Header<'_>,
+        user: String,
+    ) -> Result<Vec<Session>, ExceptError> {
+        Self::check_caller(connection, &header, Some(&user)).await?;
+        Ok(self.sessions.lock().unwrap().of_user(&user))
+    }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 4096];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) };
+    name.to_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::FcmConfig;
+    use crate::stub::{self, Stub};
+
+    // a manager with only the given notifiers, none of the bus or listener
+    fn manager(
+        registry: Arc<Mutex<Registry>>,
+        notifiers: HashMap<NotifierKind, Arc<dyn Notifier>>,
+    ) -> ExceptManager {
+        ExceptManager {
+            hostname: "test".into(),
+            active_id: Mutex::new(None),
+            requester: Mutex::new(None),
+            watcher: Mutex::new(None),
+            active_id_verified: Arc::new(AtomicBool::new(false)),
+            event: Arc::new(event_listener::Event::new()),
+            cancel: event_listener::Event::new(),
+            google_creds: None,
+            notifiers,
+            notified: Mutex::new(vec![]),
+            request: AtomicU64::new(0),
+            health: Arc::new(Health::new("127.0.0.1:0".into())),
+            registry,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            delivery: DeliveryConfig::default(),
+            last_push: AtomicU64::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn unregistered_device_is_marked_dead() {
+        let fcm = Stub::start(|req| match req.path.as_str() {
+            "/token" => (
+                200,
+                json!({"access_token": "stub-token", "expires_in": 3600}),
+            ),
+            _ => (
+                404,
+                json!({"error": {
+                    "code": 404,
+                    "message": "UNREGISTERED",
+                    "status": "NOT_FOUND",
+                    "details": [{
+                        "@type": "type.googleapis.com/google.firebase.fcm.v1.FcmError",
+                        "errorCode": "UNREGISTERED",
+                    }],
+                }}),
+            ),
+        })
+        .await;
+        let dir = stub::temp_dir("unregistered");
+        let client = reqwest::Client::new();
+        let account = stub::service_account(&dir, &format!("{}/token", fcm.url));
+        let creds = Credentials::from_file(account, client.clone()).unwrap();
+        let config = FcmConfig {
+            base_url: Some(fcm.url.clone()),
+            ..Default::default()
+        };
+        let notifier = FcmNotifier::new(client, Arc::new(creds), &config).unwrap();
+
+        let path = dir.join("devices.json");
+        let registry = Arc::new(Mutex::new(Registry::new(&path)));
+        let device = registry
+            .lock()
+            .unwrap()
+            .enroll("phone", "alice", NotifierKind::Fcm, Some("stale".into()))
+            .unwrap()
+            .clone();
+        let notifiers =
+            HashMap::from([(NotifierKind::Fcm, Arc::new(notifier) as Arc<dyn Notifier>)]);
+        let manager = manager(registry.clone(), notifiers);
+
+        let err = manager.wake(&device).await.unwrap_err();
+        assert!(matches!(err, ExceptError::PushFailed(_)), "{:?}", err);
+        assert!(!registry.lock().unwrap().get(device.id).unwrap().reachable());
+        // UNREGISTERED is not retried and the dead address is saved
+        let requests = fcm.requests();
+        let sends: Vec<_> = requests
+            .iter()
+            .filter(|r| r.path.ends_with("/messages:send"))
+            .collect();
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0].method, "POST");
+        assert_eq!(sends[0].header("Authorization"), Some("Bearer stub-token"));
+        assert_eq!(sends[0].json()["message"]["token"], "stale");
+        let mut reloaded = Registry::new(&path);
+        reloaded.load().unwrap();
+        assert!(!reloaded.get(device.id).unwrap().reachable());
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
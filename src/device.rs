@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, digest, hkdf, hmac};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::health;
+use crate::notifier::{NotifierKind, WebPushSubscription};
+use crate::session::Sessions;
+
+pub const TOKEN_ROTATE: u8 = 84;
+pub const CHALLENGE_CLAIM: u8 = 67;
+pub const SESSIONS_LIST: u8 = 76;
+const TOKEN_ROTATED: u8 = 65;
+const TOKEN_REJECTED: u8 = 83;
+const SESSIONS_LISTED: u8 = 65;
+const SESSIONS_REJECTED: u8 = 83;
+const MAX_TOKEN_LEN: usize = 4096;
+const MAX_CLOCK_SKEW: u64 = 300;
+const PAYLOAD_KEY_INFO: &[u8] = b"except push payload";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Device {
+    pub(crate) id: u8,
+    pub(crate) name: String,
+    // the account the device approves logins for, devices enrolled before
+    // users were tracked belong to nobody
+    #[serde(default)]
+    pub(crate) user: Option<String>,
+    #[serde(default)]
+    pub(crate) notifier: NotifierKind,
+    pub(crate) fcm_token: Option<String>,
+    #[serde(default)]
+    pub(crate) unifiedpush_endpoint: Option<String>,
+    #[serde(default)]
+    pub(crate) webpush: Option<WebPushSubscription>,
+    // set once the notifier reports the device's address as gone, cleared
+    // when the address is updated
+    #[serde(default, alias = "fcm_token_dead")]
+    pub(crate) push_dead: bool,
+    // revoked devices can't approve logins or rotate their token anymore
+    #[serde(default)]
+    pub(crate) revoked: bool,
+    // hex encoded HMAC-SHA256 key shared with the device at enrollment
+    #[serde(default)]
+    pub(crate) key: String,
+}
+
+impl Device {
+    pub(crate) fn belongs_to(&self, user: &str) -> bool {
+        self.user.as_deref() == Some(user)
+    }
+
+    pub(crate) fn reachable(&self) -> bool {
+        let address = match self.notifier {
+            NotifierKind::Fcm => self.fcm_token.is_some(),
+            NotifierKind::UnifiedPush => self.unifiedpush_endpoint.is_some(),
+            NotifierKind::WebPush => self.webpush.is_some(),
+        };
+        address && !self.push_dead && !self.revoked
+    }
+
+    // the fcm token, the UnifiedPush endpoint url or the browser's WebPush
+    // subscription json, depending on the notifier
+    fn set_address(&mut self, address: Option<String>) -> Result<(), Box<dyn Error>> {
+        match self.notifier {
+            NotifierKind::Fcm => self.fcm_token = address,
+            NotifierKind::UnifiedPush => {
+                if let Some(endpoint) = &address {
+                    reqwest::Url::parse(endpoint)?;
+                }
+                self.unifiedpush_endpoint = address
+            }
+            NotifierKind::WebPush => {
+                self.webpush = address.map(|a| serde_json::from_str(&a)).transpose()?
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&self, msg: &[u8], tag: &[u8]) -> Result<(), Box<dyn Error>> {
+        if self.revoked {
+            return Err("device is revoked".into());
+        }
+        let key = from_hex(&self.key).ok_or("device has no valid key")?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &key);
+        hmac::verify(&key, msg, tag).map_err(|_| "invalid message signature".into())
+    }
+
+    /// Encrypts `plaintext` so only the device can read it, the payload key
+    /// is derived from the device key rather than reusing the MAC key
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Result<Sealed, Box<dyn Error>> {
+        let key = from_hex(&self.key).ok_or("device has no valid key")?;
+        let kid = to_hex(&digest::digest(&digest::SHA256, &key).as_ref()[..8]);
+
+        let mut payload_key = [0; 32];
+        hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+            .extract(&key)
+            .expand(&[PAYLOAD_KEY_INFO], &aead::AES_256_GCM)
+            .and_then(|okm| okm.fill(&mut payload_key))
+            .map_err(|_| "failed to derive payload key")?;
+        let payload_key = aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_256_GCM, &payload_key)
+                .map_err(|_| "invalid payload key")?,
+        );
+
+        let mut nonce = [0; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "failed to generate nonce")?;
+        let mut payload = plaintext.to_vec();
+        payload_key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(kid.as_bytes()),
+                &mut payload,
+            )
+            .map_err(|_| "failed to seal payload")?;
+
+        Ok(Sealed {
+            kid,
+            nonce: BASE64URL.encode(nonce),
+            payload: BASE64URL.encode(payload),
+        })
+    }
+}
+
+/// An AES-256-GCM sealed payload, the key id is bound as associated data
+#[derive(Debug, Serialize)]
+pub(crate) struct Sealed {
+    pub(crate) kid: String,
+    pub(crate) nonce: String,
+    pub(crate) payload: String,
+}
+
+pub(crate) struct Registry {
+    path: PathBuf,
+    devices: Vec<Device>,
+    // newest timestamp accepted per device and message kind, a signed
+    // message is only taken once
+    last_signed: HashMap<(u8, u8), u64>,
+}
+
+impl Registry {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            devices: vec![],
+            last_signed: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        debug!(path = ?self.path, "loading device registry");
+        self.devices = match std::fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        debug!(devices = self.devices.len(), "device registry loaded");
+        Ok(())
+    }
+
+    // written to a temporary file and renamed so a crash never leaves a
+    // truncated registry behind
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        // the device keys are secrets, only the daemon may read them
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        // a leftover tmp file keeps the mode it was created with
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(&self.devices)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub(crate) fn enroll(
+        &mut self,
+        name: &str,
+        user: &str,
+        notifier: NotifierKind,
+        address: Option<String>,
+    ) -> Result<&Device, Box<dyn Error>> {
+        if user.is_empty() {
+            return Err("no user given".into());
+        }
+        let id = (1..=u8::MAX)
+            .find(|id| self.get(*id).is_none())
+            .ok_or("no free device ids left")?;
+        let mut key = [0; 32];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| "failed to generate device key")?;
+        let mut device = Device {
+            id,
+            name: name.into(),
+            user: Some(user.into()),
+            notifier,
+            fcm_token: None,
+            unifiedpush_endpoint: None,
+            webpush: None,
+            push_dead: false,
+            revoked: false,
+            key: to_hex(&key),
+        };
+        device.set_address(address)?;
+        self.devices.push(device);
+        if let Err(e) = self.save() {
+            // keep memory and disk in agreement
+            self.devices.pop();
+            return Err(e);
+        }
+        debug!(id, name, user, "device enrolled");
+        Ok(&self.devices[self.devices.len() - 1])
+    }
+
+    /// Replaces the push address of whichever notifier the device uses, a
+    /// device whose address was gone becomes reachable again
+    pub(crate) fn set_push_address(
+        &mut self,
+        id: u8,
+        address: String,
+    ) -> Result<(), Box<dyn Error>> {
+        let i = self
+            .devices
+            .iter()
+            .position(|d| d.id == id)
+            .ok_or("unknown device")?;
+        let previous = self.devices[i].clone();
+        self.devices[i].set_address(Some(address))?;
+        self.devices[i].push_dead = false;
+        if let Err(e) = self.save() {
+            // keep memory and disk in agreement
+            self.devices[i] = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mark_push_dead(&mut self, id: u8) -> Result<(), Box<dyn Error>> {
+        let device = self
+            .devices
+            .iter_mut()
+            .find(|d| d.id == id)
+            .ok_or("unknown device")?;
+        device.push_dead = true;
+        warn!(id, name = device.name, notifier = ?device.notifier, "push address marked as gone");
+        self.save()
+    }
+
+    pub(crate) fn revoke(&mut self, id: u8) -> Result<(), Box<dyn Error>> {
+        let device = self
+            .devices
+            .iter_mut()
+            .find(|d| d.id == id)
+            .ok_or("unknown device")?;
+        device.revoked = true;
+        info!(id, name = device.name, user = device.user, "device revoked");
+        self.save()
+    }
+
+    // checks the tag of a signed message and that it is newer than the last
+    // one of its kind, replays inside the clock skew window are turned away
+    fn verify_signed(
+        &mut self,
+        id: u8,
+        kind: u8,
+        timestamp: u64,
+        msg: &[u8],
+        tag: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.get(id).ok_or("unknown device")?.verify(msg, tag)?;
+        let last = self.last_signed.entry((id, kind)).or_default();
+        if timestamp <= *last {
+            return Err("replayed request".into());
+        }
+        *last = timestamp;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, id: u8) -> Option<&Device> {
+        self.devices.iter().find(|d| d.id == id)
+    }
+
+    pub(crate) fn default_device(&self, user: &str) -> Option<&Device> {
+        self.devices
+            .iter()
+            .find(|d| d.belongs_to(user) && d.reachable())
+    }
+
+    pub(crate) fn has_devices(&self, user: &str) -> bool {
+        self.devices.iter().any(|d| d.belongs_to(user))
+    }
+
+    pub(crate) fn has_active_devices(&self, user: &str) -> bool {
+        self.devices
+            .iter()
+            .any(|d| d.belongs_to(user) && !d.revoked)
+    }
+
+    // in enrollment order, so the default device comes first
+    pub(crate) fn reachable_devices(&self, user: &str) -> Vec<Device> {
+        self.devices
+            .iter()
+            .filter(|d| d.belongs_to(user) && d.reachable())
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.devices.len()
+    }
+}
+
+// [TOKEN_ROTATE][id][timestamp u64][length u16][token][HMAC-SHA256 of everything before it],
+// the token is whatever push address the notifier of the device uses
+pub(crate) async fn rotate_token(
+    stream: &mut TcpStream,
+    registry: &Mutex<Registry>,
+    peer: &str,
+) -> Result<u8, Box<dyn Error>> {
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+    let id = header[0];
+    let timestamp = u64::from_be_bytes(header[1..9].try_into()?);
+    let length = u16::from_be_bytes(header[9..11].try_into()?) as usize;
+    if length == 0 || length > MAX_TOKEN_LEN {
+        stream.write_all(&[TOKEN_REJECTED]).await?;
+        return Err("invalid token length".into());
+    }
+
+    let mut token = vec![0; length];
+    stream.read_exact(&mut token).await?;
+    let mut tag = [0; 32];
+    stream.read_exact(&mut tag).await?;
+
+    let mut msg = vec![TOKEN_ROTATE];
+    msg.extend(header);
+    msg.extend(&token);
+
+    let result =
+        verify_rotation(registry, id, timestamp, &msg, &tag, token).map_err(|e| e.to_string());
+    match result {
+        Ok(_) => {
+            info!(peer, id, "push address rotated");
+            stream.write_all(&[TOKEN_ROTATED]).await?;
+            Ok(id)
+        }
+        Err(e) => {
+            warn!(peer, id, "push address rotation rejected: {}", e);
+            stream.write_all(&[TOKEN_REJECTED]).await?;
+            Err(e.into())
+        }
+    }
+}
+
+fn verify_rotation(
+    registry: &Mutex<Registry>,
+    id: u8,
+    timestamp: u64,
+    msg: &[u8],
+    tag: &[u8],
+    token: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    if health::now().abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return Err("stale token rotation request".into());
+    }
+
+    let mut registry = registry.lock().unwrap();
+    registry.verify_signed(id, TOKEN_ROTATE, timestamp, msg, tag)?;
+    registry.set_push_address(id, String::from_utf8(token)?)
+}
+
+// [CHALLENGE_CLAIM][id][timestamp u64][HMAC-SHA256 of everything before it],
+// sent instead of CHALLENGE_REQUESTED by devices that say who they are
+pub(crate) async fn claim(
+    stream: &mut TcpStream,
+    registry: &Mutex<Registry>,
+) -> Result<u8, Box<dyn Error>> {
+    read_signed(stream, registry, CHALLENGE_CLAIM).await
+}
+
+// [SESSIONS_LIST][id][timestamp u64][HMAC-SHA256 of everything before it],
+// answered with [SESSIONS_LISTED][length u32][json array of sessions] for
+// the user the device is enrolled for
+pub(crate) async fn list_sessions(
+    stream: &mut TcpStream,
+    registry: &Mutex<Registry>,
+    sessions: &Mutex<Sessions>,
+    peer: &str,
+) -> Result<u8, Box<dyn Error>> {
+    let result = read_signed(stream, registry, SESSIONS_LIST)
+        .await
+        .and_then(|id| {
+            let registry = registry.lock().unwrap();
+            let device = registry.get(id).ok_or("unknown device")?;
+            if device.revoked {
+                return Err("device is revoked".into());
+            }
+            let user = device.user.as_deref().ok_or("device belongs to nobody")?;
+            let list = sessions.lock().unwrap().of_user(user);
+            Ok((id, serde_json::to_vec(&list)?))
+        })
+        .map_err(|e| e.to_string());
+    match result {
+        Ok((id, list)) => {
+            debug!(peer, id, "listing sessions");
+            stream.write_all(&[SESSIONS_LISTED]).await?;
+            stream.write_all(&(list.len() as u32).to_be_bytes()).await?;
+            stream.write_all(&list).await?;
+            Ok(id)
+        }
+        Err(e) => {
+            warn!(peer, "session listing rejected: {}", e);
+            stream.write_all(&[SESSIONS_REJECTED]).await?;
+            Err(e.into())
+        }
+    }
+}
+
+// [kind][id][timestamp u64][HMAC-SHA256 of everything before it], for the
+// requests that carry nothing but the device id
+async fn read_signed(
+    stream: &mut TcpStream,
+    registry: &Mutex<Registry>,
+    kind: u8,
+) -> Result<u8, Box<dyn Error>> {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header).await?;
+    let mut tag = [0; 32];
+    stream.read_exact(&mut tag).await?;
+    let id = header[0];
+    let timestamp = u64::from_be_bytes(header[1..9].try_into()?);
+    if health::now().abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return Err("stale request".into());
+    }
+
+    let mut msg = vec![kind];
+    msg.extend(header);
+    registry
+        .lock()
+        .unwrap()
+        .verify_signed(id, kind, timestamp, &msg, &tag)?;
+    Ok(id)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// the app's side of Device::seal
+#[cfg(test)]
+#[path = "../android/src/payload.rs"]
+mod android;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifier::NotifierKind;
+    use crate::stub;
+
+    // the app derives the payload key on its own, both sides have to agree
+    #[test]
+    fn sealed_payload_opens_on_the_device() {
+        let dir = stub::temp_dir("seal");
+        let mut registry = Registry::new(dir.join("devices.json"));
+        let device = registry
+            .enroll("phone", "alice", NotifierKind::Fcm, Some("token".into()))
+            .unwrap()
+            .clone();
+        let key = from_hex(&device.key).unwrap();
+
+        let sealed = device.seal(b"{\"type\":\"auth\"}").unwrap();
+        let opened =
+            android::open_payload(&key, &sealed.kid, &sealed.nonce, &sealed.payload).unwrap();
+        assert_eq!(opened, "{\"type\":\"auth\"}");
+
+        let other = registry
+            .enroll("laptop", "alice", NotifierKind::Fcm, Some("token".into()))
+            .unwrap()
+            .clone();
+        let other_key = from_hex(&other.key).unwrap();
+        let err = android::open_payload(&other_key, &sealed.kid, &sealed.nonce, &sealed.payload)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "payload sealed to a different key");
+    }
+}
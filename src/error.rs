@@ -0,0 +1,17 @@
+use zbus::DBusError;
+
+#[derive(Debug, DBusError)]
+#[zbus(prefix = "net.anunknownalias.ExceptManager.Error")]
+pub enum ExceptError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    NoDevice(String),
+    PushFailed(String),
+    Timeout(String),
+    Cancelled(String),
+    Fraud(String),
+    Busy(String),
+    PermissionDenied(String),
+    NotEnrolled(String),
+    EnrollFailed(String),
+}
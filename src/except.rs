@@ -1,15 +1,20 @@
-use std::sync::{Arc, atomic::AtomicBool};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, atomic::AtomicBool};
 use std::{str::FromStr, sync::atomic::Ordering};
 
 use event_listener::Event;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use zbus::connection;
 
-use crate::challenge::{CHALLENGE_CANCELLED, CHALLENGE_REQUESTED, Challenge};
+use crate::challenge::{
+    Answer, CHALLENGE_CANCELLED, CHALLENGE_REJECTED, CHALLENGE_REQUESTED, Challenge,
+};
+use crate::config::{BusType, Config};
 pub(crate) use crate::dbus::ExceptManager;
+use crate::device::{self, CHALLENGE_CLAIM, Registry, SESSIONS_LIST, TOKEN_ROTATE};
+use crate::health::{Health, LISTENER_FAILED, LISTENER_LISTENING};
+use crate::session::Sessions;
 
 const DBUS_NAME: &str = "net.anunknownalias.ExceptManager";
 const DBUS_PATH: &str = "/net/anunknownalias/ExceptManager";
@@ -17,37 +22,60 @@ const DBUS_PATH: &str = "/net/anunknownalias/ExceptManager";
 pub struct Except {
     ip: std::net::Ipv4Addr,
     port: u16,
+    config: Config,
 
     event: Arc<event_listener::Event>,
-    tx: tokio::sync::broadcast::Sender<u8>,
     verified: Arc<std::sync::atomic::AtomicBool>,
+    health: Arc<Health>,
+    registry: Arc<Mutex<Registry>>,
+    sessions: Arc<Mutex<Sessions>>,
     dbus: Option<zbus::Connection>,
 }
 
 impl Except {
-    pub fn new(ip: &str, port: u16) -> Self {
-        let ip = std::net::Ipv4Addr::from_str(ip).unwrap();
+    pub fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let ip = std::net::Ipv4Addr::from_str(&config.address)
+            .map_err(|e| format!("invalid address {:?} in config: {}", config.address, e))?;
+        let port = config.port;
         let event = Arc::new(Event::new());
-        let (tx, _) = tokio::sync::broadcast::channel(2);
         let verified = Arc::new(AtomicBool::new(false));
-        Self {
+        let health = Arc::new(Health::new(format!("{}:{}", ip, port)));
+        let registry = Arc::new(Mutex::new(Registry::new(config.registry_path()?)));
+        Ok(Self {
             ip,
             port,
+            config,
             event,
-            tx,
             verified,
+            health,
+            registry,
+            sessions: Arc::new(Mutex::new(Sessions::default())),
             dbus: None,
-        }
+        })
     }
 
     pub async fn dbus_connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.registry.lock().unwrap().load()?;
+
         debug!(DBUS_NAME, DBUS_PATH, "starting dbus service");
-        let dbus = ExceptManager::new(self.event.clone(), self.tx.clone(), self.verified.clone());
-        let connection = connection::Builder::session()?
+        let dbus = ExceptManager::new(
+            self.event.clone(),
+            self.verified.clone(),
+            self.health.clone(),
+            self.registry.clone(),
+            self.sessions.clone(),
+            &self.config,
+        )?;
+        let (builder, bus_type) = match self.config.bus {
+            BusType::Session => (connection::Builder::session()?, "session"),
+            BusType::System => (connection::Builder::system()?, "system"),
+        };
+        let connection = builder
             .name(DBUS_NAME)?
             .serve_at(DBUS_PATH, dbus)?
             .build()
             .await?;
+        self.health.set_bus_type(bus_type);
 
         self.dbus = Some(connection);
         Ok(())
@@ -58,17 +86,28 @@ impl Except {
             ip = self.ip.to_string(),
             self.port, "opening the tcp listener"
         );
-        let listener = TcpListener::bind((self.ip, self.port)).await?;
+        let listener = match TcpListener::bind((self.ip, self.port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                self.health.set_listener_state(LISTENER_FAILED);
+                return Err(e.into());
+            }
+        };
+        self.health.set_listener_state(LISTENER_LISTENING);
         loop {
             let (socket, _) = listener.accept().await?;
             info!("accepted connection from: {}", socket.peer_addr()?.ip());
 
-            let rx = self.tx.subscribe();
             let event = self.event.clone();
             let verified = self.verified.clone();
+            let health = self.health.clone();
+            let registry = self.registry.clone();
+            let sessions = self.sessions.clone();
             debug!("spawning a new client handling task");
             tokio::spawn(async move {
-                if let Err(e) = Except::handle_client(socket, rx, event, verified).await {
+                if let Err(e) =
+                    Except::handle_client(socket, event, verified, health, registry, sessions).await
+                {
                     error!("an error occurred; error = {:?}", e);
                 }
             });
@@ -77,29 +116,61 @@ impl Except {
 
     async fn handle_client(
         mut stream: TcpStream,
-        mut rx: tokio::sync::broadcast::Receiver<u8>,
         event: Arc<event_listener::Event>,
         verified: Arc<std::sync::atomic::AtomicBool>,
+        health: Arc<Health>,
+        registry: Arc<Mutex<Registry>>,
+        sessions: Arc<Mutex<Sessions>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut buf = [0; 1];
-        let mut peek_buf = [0; 1];
         stream.read_exact(&mut buf).await?;
 
-        debug!("notifying the dbus manager and waiting for the device id");
-        let recv = rx.recv();
-        event.notify(1);
-        tokio::select! {
-                _ = tokio::time::sleep(Duration::from_secs(10)) => {
-                    return Err("stream timeout".into());
-                },
-                _ = stream.peek(&mut peek_buf) => {
-                    return Err("invalid stream sequence".into());
-                }
-                Ok(id) = recv => {
-                    Except::client_requests(&buf, id, stream, verified).await?;
-                }
+        if buf[0] == TOKEN_ROTATE {
+            let peer = stream.peer_addr()?.to_string();
+            debug!(peer, "received push address rotation");
+            let id = device::rotate_token(&mut stream, &registry, &peer).await?;
+            health.device_seen(id);
+            return Ok(());
         }
-        Ok(())
+
+        if buf[0] == SESSIONS_LIST {
+            let peer = stream.peer_addr()?.to_string();
+            let id = device::list_sessions(&mut stream, &registry, &sessions, &peer).await?;
+            health.device_seen(id);
+            return Ok(());
+        }
+
+        if buf[0] == CHALLENGE_CLAIM {
+            let peer = stream.peer_addr()?.to_string();
+            let id = device::claim(&mut stream, &registry)
+                .await
+                .map_err(|e| format!("invalid challenge claim from {}: {}", peer, e))?;
+            debug!(peer, id, "device claimed the challenge");
+            health.device_seen(id);
+            // only a device of the requesting user woken for this request
+            let woken = health.woken_for(id).is_some_and(|user| {
+                let registry = registry.lock().unwrap();
+                registry.get(id).is_some_and(|d| d.belongs_to(&user))
+            });
+            if !woken {
+                stream.write_all(&[CHALLENGE_REJECTED]).await?;
+                return Err(format!(
+                    "challenge claim from {} for device {} not woken for a pending request",
+                    peer, id
+                )
+                .into());
+            }
+            event.notify(1);
+            return Except::client_requests(&[CHALLENGE_REQUESTED], id, stream, verified, health)
+                .await;
+        }
+
+        // a challenge needs a signed claim, an unclaimed one can't be tied to
+        // a device woken for the request
+        if buf[0] == CHALLENGE_REQUESTED {
+            stream.write_all(&[CHALLENGE_REJECTED]).await?;
+        }
+        Err(format!("unclaimed request {} from {}", buf[0], stream.peer_addr()?).into())
     }
 
     async fn client_requests(
@@ -107,6 +178,7 @@ impl Except {
         id: u8,
         mut stream: TcpStream,
         verified: Arc<AtomicBool>,
+        health: Arc<Health>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let peer = stream.peer_addr()?.to_string();
         match buf[0] {
@@ -116,10 +188,39 @@ impl Except {
                 Ok(())
             }
             CHALLENGE_REQUESTED => {
+                // another device already answered, the rest are turned away
+                if verified.load(Ordering::Acquire) || health.refused_by().is_some() {
+                    debug!(peer, id, "request already answered");
+                    stream.write_all(&[CHALLENGE_REJECTED]).await?;
+                    return Ok(());
+                }
                 debug!(peer, "received challenge request");
-                let result = Challenge::run(&mut stream, id, &peer).await?;
-                verified.store(result, Ordering::Release);
-                debug!(peer, result, "challange completed");
+                let answer = Challenge::run(&mut stream, id, &peer).await?;
+                match answer {
+                    // the request may have ended while the challenge ran
+                    Answer::Approved if !health.request_pending() => {
+                        debug!(peer, id, "approval for a request no longer pending");
+                    }
+                    Answer::Approved => {
+                        if verified
+                            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                            .is_ok()
+                        {
+                            health.set_answered_by(Some(id));
+                            info!(peer, id, "request answered");
+                        }
+                    }
+                    // only a device woken for the request may refuse it
+                    Answer::Denied | Answer::Fraud if health.woken_for(id).is_none() => {
+                        warn!(peer, id, ?answer, "refusal from a device not woken");
+                    }
+                    Answer::Denied | Answer::Fraud => {
+                        health.set_refused_by(Some((id, answer)));
+                        info!(peer, id, ?answer, "request refused");
+                    }
+                    Answer::Rejected => (),
+                }
+                debug!(peer, ?answer, "challange completed");
 
                 Ok(())
             }
@@ -1,129 +1,205 @@
+use std::error::Error;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::{self, Duration};
 
-use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
-use serde_json::json;
-use tracing::debug;
+use tracing::{debug, error};
 
+use super::token_source::{
+    ExternalAccountSource, MetadataServerSource, ServiceAccountSource, TokenSource,
+};
 use super::types::*;
-
-const DEFAULT_TOKEN_EXPIRY: Duration = Duration::from_secs(3600);
+use crate::config::FcmConfig;
+
+// access tokens are refreshed this long before they expire
+const REFRESH_AHEAD: Duration = Duration::from_secs(300);
+const REFRESH_RETRY: Duration = Duration::from_secs(30);
+// never ask the token endpoint more often than this
+const MIN_REFRESH_WAIT: Duration = Duration::from_secs(30);
+// name of the key in $CREDENTIALS_DIRECTORY, i.e. LoadCredential=except.json:...
+const CREDENTIAL_NAME: &str = "except.json";
+
+struct CachedToken {
+    access_token: String,
+    expires_at: time::SystemTime,
+}
 
 pub struct Credentials {
-    signer: EncodingKey,
-    token: Option<AuthToken>,
-    token_header: Header,
-    claims: Claims,
-    token_uri: String,
-    default_headers: reqwest::header::HeaderMap,
-    token_expiry: Duration,
+    source: Box<dyn TokenSource>,
+    client: reqwest::Client,
+    token: RwLock<Option<CachedToken>>,
+    // held for the duration of a refresh so only one runs at a time
+    refresh_lock: tokio::sync::Mutex<()>,
+    // only service account keys carry one
+    project_id: Option<String>,
 }
 
 impl Credentials {
-    pub fn from_service_account_file(f: &str) -> Self {
-        debug!("loading service account from file: {}", f);
-        let file_content = std::fs::read_to_string(f).unwrap();
-        let service_account: ServiceAccount = serde_json::from_str(&file_content).unwrap();
-        let signer = EncodingKey::from_rsa_pem(service_account.private_key.as_bytes()).unwrap();
-        let token_uri = service_account.token_uri.clone();
-        let default_headers = Credentials::default_headers();
-        let (iat, exp) = Credentials::claim_iat_exp();
-
-        Credentials {
-            signer,
-            token_uri,
-            token: None,
-            default_headers,
-            token_expiry: DEFAULT_TOKEN_EXPIRY,
-            token_header: Header {
-                typ: Some("JWT".into()),
-                alg: Algorithm::RS256,
-                kid: Some(service_account.private_key_id.clone()),
-                ..Header::default()
-            },
-            claims: Claims {
-                iat,
-                exp,
-                iss: service_account.client_email.clone(),
-                aud: service_account.token_uri.clone(),
-                scope: "https://www.googleapis.com/auth/firebase.messaging".into(),
-            },
+    /// Picks the token source the fcm config asks for
+    pub fn from_config(
+        config: &FcmConfig,
+        client: reqwest::Client,
+    ) -> Result<Self, Box<dyn Error>> {
+        if config.metadata_server {
+            let source = Box::new(MetadataServerSource::new());
+            return Ok(Self::from_source(source, None, client));
         }
+        Self::from_file(Self::locate(config.credentials.as_deref())?, client)
     }
 
-    #[allow(dead_code)]
-    pub fn set_token_expiry(&mut self, exp: Duration) {
-        self.token_expiry = exp;
-        self.claims.exp = self.claims.iat + exp.as_secs();
+    /// Finds the service account key, trying `configured`, then
+    /// `GOOGLE_APPLICATION_CREDENTIALS`, then `$CREDENTIALS_DIRECTORY`
+    pub fn locate(configured: Option<&Path>) -> Result<PathBuf, Box<dyn Error>> {
+        if let Some(path) = configured {
+            return Ok(path.into());
+        }
+        if let Some(path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            return Ok(path.into());
+        }
+        if let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+            let path = Path::new(&dir).join(CREDENTIAL_NAME);
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+        Err(
+            "no service account configured, set fcm.credentials or GOOGLE_APPLICATION_CREDENTIALS"
+                .into(),
+        )
     }
 
-    pub fn get_access_token(&self) -> Result<&str, Box<dyn std::error::Error>> {
-        match self.token.as_ref() {
-            Some(t) => Ok(&t.access_token),
-            None => Err("no token found, try refreshing".into()),
+    /// Loads a service account key or an external account configuration
+    pub fn from_file(f: impl AsRef<Path>, client: reqwest::Client) -> Result<Self, Box<dyn Error>> {
+        let f = f.as_ref();
+        debug!("loading credentials from file: {:?}", f);
+        let metadata =
+            std::fs::metadata(f).map_err(|e| format!("failed to stat {:?}: {}", f, e))?;
+        if metadata.permissions().mode() & 0o004 != 0 {
+            return Err(format!("{:?} is world readable, chmod o-r it", f).into());
+        }
+        let file_content =
+            std::fs::read_to_string(f).map_err(|e| format!("failed to read {:?}: {}", f, e))?;
+        let value: serde_json::Value = serde_json::from_str(&file_content)
+            .map_err(|e| format!("{:?} is not a credentials file: {}", f, e))?;
+        match value.get("type").and_then(|t| t.as_str()) {
+            Some("service_account") => {
+                let service_account: ServiceAccount = serde_json::from_value(value)
+                    .map_err(|e| format!("{:?} is not a service account key: {}", f, e))?;
+                let source = ServiceAccountSource::new(&service_account)
+                    .map_err(|e| format!("{:?}: {}", f, e))?;
+                Ok(Self::from_source(
+                    Box::new(source),
+                    Some(service_account.project_id),
+                    client,
+                ))
+            }
+            Some("external_account") => {
+                let account: ExternalAccount = serde_json::from_value(value)
+                    .map_err(|e| format!("{:?} is not an external account: {}", f, e))?;
+                let source =
+                    ExternalAccountSource::new(account).map_err(|e| format!("{:?}: {}", f, e))?;
+                Ok(Self::from_source(Box::new(source), None, client))
+            }
+            t => Err(format!("{:?} has unsupported credentials type: {:?}", f, t).into()),
         }
     }
 
-    pub async fn refresh(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        debug!(token_uri=self.token_uri, "refreshing token");
-        let assertion = encode(&self.token_header, &self.claims, &self.signer)?;
-        let mut headers = self.default_headers.clone();
-        headers.insert("Authorization", format!("Bearer {}", assertion).parse()?);
-        self.update_claim_iat_exp()?;
-
-        debug!("sending token refresh request");
-        let res = reqwest::Client::new()
-            .post(&self.token_uri)
-            .headers(headers)
-            .body(
-                json!({
-                    "assertion": assertion,
-                    "grant_type": "urn:ietf:params:oauth:grant-type:jwt-bearer"
-                })
-                .to_string(),
-            )
-            .send()
-            .await?;
-
-        if !res.status().is_success() {
-            let msg: Message = res.json().await?;
-            return Err(format!("Failed to refresh token: {:?}", msg).into());
+    fn from_source(
+        source: Box<dyn TokenSource>,
+        project_id: Option<String>,
+        client: reqwest::Client,
+    ) -> Self {
+        Credentials {
+            source,
+            client,
+            token: RwLock::new(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            project_id,
         }
+    }
 
-        let auth_res = res.json::<AuthToken>().await?;
-        self.token = Some(auth_res);
+    pub fn project_id(&self) -> Option<&str> {
+        self.project_id.as_deref()
+    }
 
-        debug!("token refreshed successfully");
-        Ok(())
+    /// Returns the cached access token, only refreshing when it has expired
+    pub async fn get_access_token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(token) = self.cached_token(Duration::ZERO) {
+            return Ok(token);
+        }
+        self.refresh().await?;
+        self.cached_token(Duration::ZERO)
+            .ok_or_else(|| "no token found after refreshing".into())
+    }
+
+    fn cached_token(&self, valid_for: Duration) -> Option<String> {
+        let token = self.token.read().unwrap();
+        token
+            .as_ref()
+            .filter(|t| t.expires_at > time::SystemTime::now() + valid_for)
+            .map(|t| t.access_token.clone())
     }
 
-    fn update_claim_iat_exp(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.claims.iat = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        self.claims.exp = self.claims.iat + self.token_expiry.as_secs();
+    pub fn token_expires_at(&self) -> Option<time::SystemTime> {
+        self.token.read().unwrap().as_ref().map(|t| t.expires_at)
+    }
 
-        Ok(())
+    pub fn token_valid(&self) -> bool {
+        self.cached_token(Duration::ZERO).is_some()
     }
 
-    fn claim_iat_exp() -> (u64, u64) {
-        let iat = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(d) => d,
-            Err(e) => panic!("Failed to get system time for issued at claim: {}", e),
-        };
-        let exp = iat + DEFAULT_TOKEN_EXPIRY;
-        (iat.as_secs(), exp.as_secs())
+    /// Keeps the cached token fresh, refreshing it ahead of its expiry
+    pub fn spawn_refresh(self: &Arc<Self>) {
+        let creds = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let wait = match creds.token_expires_at() {
+                    // nothing cached yet
+                    None => Duration::ZERO,
+                    Some(exp) => {
+                        let left = exp
+                            .duration_since(time::SystemTime::now())
+                            .unwrap_or_default();
+                        // tokens living shorter than REFRESH_AHEAD are
+                        // refreshed halfway through instead
+                        left.checked_sub(REFRESH_AHEAD)
+                            .filter(|wait| !wait.is_zero())
+                            .unwrap_or(left / 2)
+                            .max(MIN_REFRESH_WAIT)
+                    }
+                };
+                tokio::time::sleep(wait).await;
+
+                let res = creds.refresh().await.map_err(|e| e.to_string());
+                if let Err(e) = res {
+                    error!("background token refresh failed: {}", e);
+                    tokio::time::sleep(REFRESH_RETRY).await;
+                }
+            }
+        });
     }
 
-    fn default_headers() -> reqwest::header::HeaderMap {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert("Content-Type", "application/json".parse().unwrap());
-        headers.insert(
-            "x-goog-api-client",
-            "random-project/1.2.3 self-rolled/1.2.3 auth-request-type/at cred-type/sa"
-                .parse()
-                .unwrap(),
-        );
-        headers
+    pub async fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let _guard = self.refresh_lock.lock().await;
+        // someone else may have refreshed while we waited on the lock
+        if self.cached_token(REFRESH_AHEAD).is_some() {
+            debug!("token already refreshed");
+            return Ok(());
+        }
+
+        debug!("refreshing token");
+        let auth_res = self
+            .source
+            .fetch(&self.client)
+            .await
+            .map_err(|e| format!("failed to refresh token: {}", e))?;
+        *self.token.write().unwrap() = Some(CachedToken {
+            expires_at: time::SystemTime::now() + Duration::from_secs(auth_res.expires_in),
+            access_token: auth_res.access_token,
+        });
+
+        debug!("token refreshed successfully");
+        Ok(())
     }
 }
@@ -1,29 +1,94 @@
 mod credentials;
 pub use credentials::Credentials;
+mod token_source;
 mod types;
-use tracing::debug;
 pub use types::*;
 
-pub async fn send_message(token: &str, msg: FCMMessage) -> Result<(), Box<dyn std::error::Error>> {
-    let url = "https://fcm.googleapis.com/v1/projects/persephone-b9fbe/messages:send";
+use std::time::Duration;
+
+use rand::Rng;
+use tracing::{debug, warn};
+
+const MAX_SEND_ATTEMPTS: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(16);
+
+pub const FCM_BASE_URL: &str = "https://fcm.googleapis.com";
+
+pub fn send_url(base_url: &str, project_id: &str) -> String {
+    format!(
+        "{}/v1/projects/{}/messages:send",
+        base_url.trim_end_matches('/'),
+        project_id
+    )
+}
+
+/// Sends `msg`, retrying transient failures with jittered exponential backoff
+pub async fn send_message(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+    msg: &FCMMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let delay = match try_send_message(client, url, token, msg).await {
+            Ok(()) => return Ok(()),
+            Err(e) => match e.downcast_ref::<FCMError>() {
+                // a login is waiting on the push, a server asking for a
+                // longer pause than MAX_BACKOFF is not waited for
+                Some(err) if err.retry_after.is_some_and(|after| after > MAX_BACKOFF) => {
+                    warn!(retry_after = ?err.retry_after, "fcm send failed, not retrying: {}", err);
+                    return Err(e);
+                }
+                Some(err) if err.is_transient() && attempt < MAX_SEND_ATTEMPTS => {
+                    let delay = err.retry_after.unwrap_or_else(|| backoff(attempt));
+                    warn!(attempt, ?delay, "fcm send failed, retrying: {}", err);
+                    delay
+                }
+                _ => return Err(e),
+            },
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    let max = BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0..=max.as_millis() as u64);
+    Duration::from_millis(jitter)
+}
+
+async fn try_send_message(
+    client: &reqwest::Client,
+    url: &str,
+    token: &str,
+    msg: &FCMMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert("Content-Type", "application/json".parse()?);
     headers.insert("Authorization", format!("Bearer {}", token).parse()?);
 
-    debug!(
-        device_token = msg.message.token,
-        "sending fcm message"
-    );
-    let body = serde_json::to_string(&msg)?;
-    let res = reqwest::Client::new()
-        .post(url)
-        .headers(headers)
-        .body(body)
-        .send()
-        .await?;
+    debug!(url, device_token = msg.message.token, "sending fcm message");
+    let body = serde_json::to_string(msg)?;
+    let res = client.post(url).headers(headers).body(body).send().await?;
 
     if !res.status().is_success() {
-        let err = res.json::<FCMError>().await?;
+        let status = res.status().as_u16();
+        let retry_after = res
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        let mut err = match res.json::<FCMError>().await {
+            Ok(err) => err,
+            Err(_) => FCMError::from_http_status(status),
+        };
+        err.retry_after = retry_after;
         return Err(Box::new(err));
     }
 
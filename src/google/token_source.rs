@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{self, Duration};
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use serde_json::json;
+use tracing::debug;
+
+use super::types::*;
+use crate::http::USER_AGENT;
+
+pub(crate) const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+pub(crate) const METADATA_HOST: &str = "metadata.google.internal";
+const JWT_BEARER_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const DEFAULT_TOKEN_EXPIRY: Duration = Duration::from_secs(3600);
+
+// x-goog-api-client of the token requests, cred-type tells google which flow
+fn api_client(cred_type: &str) -> String {
+    format!(
+        "{} auth-request-type/at cred-type/{}",
+        USER_AGENT, cred_type
+    )
+}
+
+pub(crate) type TokenResult<T = AuthToken> = Result<T, Box<dyn Error + Send + Sync>>;
+pub(crate) type TokenFuture<'a> = Pin<Box<dyn Future<Output = TokenResult> + Send + 'a>>;
+
+/// Somewhere an OAuth access token for the fcm scope can be fetched from
+pub(crate) trait TokenSource: Send + Sync {
+    fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> TokenFuture<'a>;
+}
+
+/// RFC 7523 JWT bearer grant signed with a service account key
+pub(crate) struct ServiceAccountSource {
+    signer: EncodingKey,
+    header: Header,
+    issuer: String,
+    token_uri: String,
+}
+
+impl ServiceAccountSource {
+    pub(crate) fn new(service_account: &ServiceAccount) -> Result<Self, Box<dyn Error>> {
+        let signer = EncodingKey::from_rsa_pem(service_account.private_key.as_bytes())
+            .map_err(|e| format!("invalid private key: {}", e))?;
+        Ok(Self {
+            signer,
+            header: Header {
+                typ: Some("JWT".into()),
+                alg: Algorithm::RS256,
+                kid: Some(service_account.private_key_id.clone()),
+                ..Header::default()
+            },
+            issuer: service_account.client_email.clone(),
+            token_uri: service_account.token_uri.clone(),
+        })
+    }
+
+    fn claims(&self) -> Claims {
+        let iat = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Claims {
+            iat,
+            exp: iat + DEFAULT_TOKEN_EXPIRY.as_secs(),
+            iss: self.issuer.clone(),
+            aud: self.token_uri.clone(),
+            scope: FCM_SCOPE.into(),
+        }
+    }
+
+    async fn fetch_token(&self, client: &reqwest::Client) -> TokenResult {
+        let assertion = encode(&self.header, &self.claims(), &self.signer)?;
+        debug!(token_uri = self.token_uri, "requesting jwt bearer grant");
+        let res = client
+            .post(&self.token_uri)
+            .header("x-goog-api-client", api_client("sa"))
+            .form(&[("grant_type", JWT_BEARER_GRANT), ("assertion", &assertion)])
+            .send()
+            .await?;
+        token_response(res).await
+    }
+}
+
+impl TokenSource for ServiceAccountSource {
+    fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> TokenFuture<'a> {
+        Box::pin(self.fetch_token(client))
+    }
+}
+
+/// The GCE/GKE metadata server of the default service account
+pub(crate) struct MetadataServerSource {
+    base_url: String,
+}
+
+impl MetadataServerSource {
+    // GCE_METADATA_HOST is how the google client libraries are pointed elsewhere
+    pub(crate) fn new() -> Self {
+        let host = std::env::var("GCE_METADATA_HOST").unwrap_or(METADATA_HOST.into());
+        Self {
+            base_url: format!("http://{}/computeMetadata/v1", host),
+        }
+    }
+
+    async fn fetch_token(&self, client: &reqwest::Client) -> TokenResult {
+        let url = format!("{}/instance/service-accounts/default/token", self.base_url);
+        debug!(url, "requesting token from the metadata server");
+        let res = client
+            .get(url)
+            .query(&[("scopes", FCM_SCOPE)])
+            .header("Metadata-Flavor", "Google")
+            .header("x-goog-api-client", api_client("mds"))
+            .send()
+            .await?;
+        token_response(res).await
+    }
+}
+
+impl TokenSource for MetadataServerSource {
+    fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> TokenFuture<'a> {
+        Box::pin(self.fetch_token(client))
+    }
+}
+
+/// Workload identity federation, a third party token is exchanged at the
+/// google sts for an access token, optionally impersonating a service account
+pub(crate) struct ExternalAccountSource {
+    account: ExternalAccount,
+}
+
+impl ExternalAccountSource {
+    pub(crate) fn new(account: ExternalAccount) -> Result<Self, Box<dyn Error>> {
+        let source = &account.credential_source;
+        if source.file.is_none() && source.url.is_none() {
+            return Err("credential_source needs a file or url".into());
+        }
+        Ok(Self { account })
+    }
+
+    async fn subject_token(&self, client: &reqwest::Client) -> TokenResult<String> {
+        let source = &self.account.credential_source;
+        let raw = match (&source.file, &source.url) {
+            (Some(file), _) => tokio::fs::read_to_string(file)
+                .await
+                .map_err(|e| format!("failed to read subject token {}: {}", file, e))?,
+            (None, Some(url)) => {
+                let mut req = client.get(url);
+                for (name, value) in &source.headers {
+                    req = req.header(name, value);
+                }
+                req.send().await?.error_for_status()?.text().await?
+            }
+            (None, None) => return Err("credential_source needs a file or url".into()),
+        };
+        match &source.format {
+            Some(CredentialFormat {
+                format_type,
+                subject_token_field_name: Some(field),
+            }) if format_type == "json" => {
+                let value: HashMap<String, serde_json::Value> = serde_json::from_str(&raw)?;
+                value
+                    .get(field)
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+                    .ok_or_else(|| format!("no {} in the subject token", field).into())
+            }
+            _ => Ok(raw.trim().to_string()),
+        }
+    }
+
+    async fn fetch_token(&self, client: &reqwest::Client) -> TokenResult {
+        let subject_token = self.subject_token(client).await?;
+        let scope = match self.account.service_account_impersonation_url {
+            // the federated token only needs to be able to impersonate
+            Some(_) => "https://www.googleapis.com/auth/cloud-platform",
+            None => FCM_SCOPE,
+        };
+        debug!(
+            token_url = self.account.token_url,
+            "exchanging subject token"
+        );
+        let res = client
+            .post(&self.account.token_url)
+            .header("x-goog-api-client", api_client("external"))
+            .form(&[
+                ("grant_type", TOKEN_EXCHANGE_GRANT),
+                ("audience", &self.account.audience),
+                ("scope", scope),
+                ("requested_token_type", ACCESS_TOKEN_TYPE),
+                ("subject_token", &subject_token),
+                ("subject_token_type", &self.account.subject_token_type),
+            ])
+            .send()
+            .await?;
+        let federated = token_response(res).await?;
+
+        let Some(url) = &self.account.service_account_impersonation_url else {
+            return Ok(federated);
+        };
+        debug!(url, "impersonating service account");
+        let res = client
+            .post(url)
+            .header("x-goog-api-client", api_client("imp"))
+            .bearer_auth(&federated.access_token)
+            .json(&json!({
+                "scope": [FCM_SCOPE],
+                "lifetime": format!("{}s", DEFAULT_TOKEN_EXPIRY.as_secs()),
+            }))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(format!("impersonation failed with {}", res.status()).into());
+        }
+        let impersonated: ImpersonatedToken = res.json().await?;
+        Ok(AuthToken::new(
+            impersonated.access_token,
+            DEFAULT_TOKEN_EXPIRY.as_secs(),
+        ))
+    }
+}
+
+impl TokenSource for ExternalAccountSource {
+    fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> TokenFuture<'a> {
+        Box::pin(self.fetch_token(client))
+    }
+}
+
+async fn token_response(res: reqwest::Response) -> TokenResult {
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        return Err(format!("token request failed with {}: {}", status, body).into());
+    }
+    Ok(res.json::<AuthToken>().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::stub::{self, Stub};
+
+    const SUBJECT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+    fn external_account(account: Value) -> ExternalAccountSource {
+        ExternalAccountSource::new(serde_json::from_value(account).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metadata_server_host_is_configurable() {
+        let metadata = Stub::start(|_| {
+            (
+                200,
+                json!({"access_token": "mds-token", "expires_in": 3599}),
+            )
+        })
+        .await;
+        // SAFETY: no other test reads or writes GCE_METADATA_HOST, and it is
+        // removed again as soon as the source has read it
+        unsafe {
+            std::env::set_var(
+                "GCE_METADATA_HOST",
+                metadata.url.trim_start_matches("http://"),
+            );
+        }
+        let source = MetadataServerSource::new();
+        unsafe { std::env::remove_var("GCE_METADATA_HOST") };
+
+        let token = source.fetch(&reqwest::Client::new()).await.unwrap();
+        assert_eq!(token.access_token, "mds-token");
+        let requests = metadata.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "GET");
+        let url = reqwest::Url::parse(&format!("http://stub{}", requests[0].path)).unwrap();
+        assert_eq!(
+            url.path(),
+            "/computeMetadata/v1/instance/service-accounts/default/token"
+        );
+        assert_eq!(
+            url.query_pairs().collect::<Vec<_>>(),
+            [("scopes".into(), FCM_SCOPE.into())]
+        );
+        assert_eq!(requests[0].header("Metadata-Flavor"), Some("Google"));
+    }
+
+    #[tokio::test]
+    async fn file_subject_token_is_exchanged() {
+        let sts = Stub::start(|_| {
+            (
+                200,
+                json!({"access_token": "sts-token", "expires_in": 3600}),
+            )
+        })
+        .await;
+        let dir = stub::temp_dir("external-file");
+        let file = dir.join("subject_token");
+        std::fs::write(&file, "oidc-token\n").unwrap();
+        let source = external_account(json!({
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/p/providers/o",
+            "subject_token_type": SUBJECT_TOKEN_TYPE,
+            "token_url": format!("{}/v1/token", sts.url),
+            "credential_source": {"file": file},
+        }));
+
+        let token = source.fetch(&reqwest::Client::new()).await.unwrap();
+        assert_eq!(token.access_token, "sts-token");
+        let requests = sts.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v1/token");
+        let exchange = &requests[0];
+        assert_eq!(
+            exchange.form("grant_type").as_deref(),
+            Some(TOKEN_EXCHANGE_GRANT)
+        );
+        assert_eq!(
+            exchange.form("audience").as_deref(),
+            Some(
+                "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/p/providers/o"
+            )
+        );
+        // without impersonation the federated token is used for fcm itself
+        assert_eq!(exchange.form("scope").as_deref(), Some(FCM_SCOPE));
+        assert_eq!(
+            exchange.form("requested_token_type").as_deref(),
+            Some(ACCESS_TOKEN_TYPE)
+        );
+        assert_eq!(
+            exchange.form("subject_token").as_deref(),
+            Some("oidc-token")
+        );
+        assert_eq!(
+            exchange.form("subject_token_type").as_deref(),
+            Some(SUBJECT_TOKEN_TYPE)
+        );
+    }
+
+    #[tokio::test]
+    async fn url_subject_token_is_exchanged_and_impersonated() {
+        let google = Stub::start(|req| match req.path.as_str() {
+            "/subject" => (200, json!({"id_token": "oidc-token"})),
+            "/v1/token" => (
+                200,
+                json!({"access_token": "sts-token", "expires_in": 3600}),
+            ),
+            _ => (
+                200,
+                json!({"accessToken": "sa-token", "expireTime": "2030-01-01T00:00:00Z"}),
+            ),
+        })
+        .await;
+        let source = external_account(json!({
+            "audience": "aud",
+            "subject_token_type": SUBJECT_TOKEN_TYPE,
+            "token_url": format!("{}/v1/token", google.url),
+            "credential_source": {
+                "url": format!("{}/subject", google.url),
+                "headers": {"Metadata": "True"},
+                "format": {"type": "json", "subject_token_field_name": "id_token"},
+            },
+            "service_account_impersonation_url": format!(
+                "{}/v1/projects/-/serviceAccounts/except@p.iam.gserviceaccount.com:generateAccessToken",
+                google.url
+            ),
+        }));
+
+        let token = source.fetch(&reqwest::Client::new()).await.unwrap();
+        assert_eq!(token.access_token, "sa-token");
+        let requests = google.requests();
+        let [subject, exchange, impersonation] = &requests[..] else {
+            panic!("expected three requests, got {:?}", requests);
+        };
+        assert_eq!(subject.method, "GET");
+        assert_eq!(subject.header("Metadata"), Some("True"));
+        assert_eq!(
+            exchange.form("subject_token").as_deref(),
+            Some("oidc-token")
+        );
+        assert_eq!(
+            exchange.form("scope").as_deref(),
+            Some("https://www.googleapis.com/auth/cloud-platform")
+        );
+        assert!(impersonation.path.ends_with(":generateAccessToken"));
+        assert_eq!(
+            impersonation.header("Authorization"),
+            Some("Bearer sts-token")
+        );
+        assert_eq!(impersonation.json()["scope"], json!([FCM_SCOPE]));
+        assert_eq!(impersonation.json()["lifetime"], "3600s");
+    }
+}
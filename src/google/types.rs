@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
@@ -21,32 +22,104 @@ pub enum Priority {
     High,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct AndroidConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub collapse_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub priority: Option<Priority>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ttl: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub restricted_package_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub notification: Option<Notification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub fcm_options: Option<FCMOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub direct_boot_ok: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Message {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub notification: Option<Notification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub android: Option<AndroidConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FCMMessage {
-    validate_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validate_only: Option<bool>,
     pub message: Message,
 }
 
+impl FCMMessage {
+    /// Starts a data only message addressed to the device registration `token`
+    pub fn builder(token: impl Into<String>) -> FCMMessageBuilder {
+        FCMMessageBuilder {
+            validate_only: None,
+            token: token.into(),
+            android: AndroidConfig::default(),
+        }
+    }
+}
+
+pub struct FCMMessageBuilder {
+    validate_only: Option<bool>,
+    token: String,
+    android: AndroidConfig,
+}
+
+impl FCMMessageBuilder {
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.android.priority = Some(priority);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.android.ttl = Some(format!("{}s", ttl.as_secs()));
+        self
+    }
+
+    pub fn collapse_key(mut self, key: impl Into<String>) -> Self {
+        self.android.collapse_key = Some(key.into());
+        self
+    }
+
+    pub fn data(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.android
+            .data
+            .get_or_insert_with(HashMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    // fcm validates the message without delivering it
+    pub fn validate_only(mut self, validate_only: bool) -> Self {
+        self.validate_only = Some(validate_only);
+        self
+    }
+
+    pub fn build(self) -> FCMMessage {
+        FCMMessage {
+            validate_only: self.validate_only,
+            message: Message {
+                token: Some(self.token),
+                android: Some(self.android),
+                ..Message::default()
+            },
+        }
+    }
+}
+
 impl TryFrom<String> for FCMMessage {
     type Error = Box<dyn std::error::Error>;
     fn try_from(s: String) -> Result<Self, Box<dyn std::error::Error>> {
@@ -72,7 +145,7 @@ pub(crate) struct ServiceAccount {
 
     #[serde(rename = "type")]
     _type: String,
-    project_id: String,
+    pub(crate) project_id: String,
     client_id: String,
     auth_uri: String,
     auth_provider_x509_cert_url: String,
@@ -80,7 +153,7 @@ pub(crate) struct ServiceAccount {
     universe_domain: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Claims {
     pub(crate) iat: u64,
     pub(crate) exp: u64,
@@ -89,17 +162,59 @@ pub(crate) struct Claims {
     pub(crate) scope: String,
 }
 
+/// An `external_account` credential file, only the file and url sources
+/// are supported
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExternalAccount {
+    pub(crate) audience: String,
+    pub(crate) subject_token_type: String,
+    pub(crate) token_url: String,
+    pub(crate) credential_source: CredentialSource,
+    pub(crate) service_account_impersonation_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CredentialSource {
+    pub(crate) file: Option<String>,
+    pub(crate) url: Option<String>,
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) format: Option<CredentialFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CredentialFormat {
+    #[serde(rename = "type")]
+    pub(crate) format_type: String,
+    pub(crate) subject_token_field_name: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct AuthToken {
     pub(crate) access_token: String,
+    #[serde(default)]
     token_type: String,
-    expires_in: u64,
+    pub(crate) expires_in: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Status {
-    #[serde(rename = "UNSPECIFIED_ERROR")]
-    UnspecifiedError,
+impl AuthToken {
+    pub(crate) fn new(access_token: String, expires_in: u64) -> Self {
+        Self {
+            access_token,
+            token_type: "Bearer".into(),
+            expires_in,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImpersonatedToken {
+    pub(crate) access_token: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Status {
     #[serde(rename = "INVALID_ARGUMENT")]
     InvalidArgument,
     #[serde(rename = "UNREGISTERED")]
@@ -114,34 +229,77 @@ enum Status {
     Internal,
     #[serde(rename = "THIRD_PARTY_AUTH_ERROR")]
     ThirdPartyAuthError,
+    // anything fcm adds later ends up here too
+    #[serde(rename = "UNSPECIFIED_ERROR", other)]
+    UnspecifiedError,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct FieldViolations {
     field: String,
-    description: String
+    description: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct ErrorDetails {
     #[serde(rename = "@type")]
     _type: String,
     field_violations: Option<Vec<FieldViolations>>,
-    error_code: Option<String>
+    error_code: Option<Status>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct FCMInnerError {
     code: i32,
     message: String,
-    status: Status,
-    details: Vec<ErrorDetails>
+    // the canonical google.rpc code, the fcm specific one is in the details
+    status: String,
+    #[serde(default)]
+    details: Vec<ErrorDetails>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct FCMError {
     #[serde(rename = "error")]
-    inner: FCMInnerError
+    inner: FCMInnerError,
+    #[serde(skip)]
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl FCMError {
+    // used when the response body isn't an fcm error, e.g. from a proxy
+    pub(crate) fn from_http_status(code: u16) -> Self {
+        FCMError {
+            inner: FCMInnerError {
+                code: code.into(),
+                message: format!("fcm request failed with http status {}", code),
+                status: String::new(),
+                details: vec![],
+            },
+            retry_after: None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> Status {
+        let code = self.inner.details.iter().find_map(|d| d.error_code);
+        match (code, self.inner.code) {
+            (Some(code), _) => code,
+            (None, 400) => Status::InvalidArgument,
+            (None, 429) => Status::QuotaExceeded,
+            (None, 500) => Status::Internal,
+            (None, 503) => Status::Unavailable,
+            _ => Status::UnspecifiedError,
+        }
+    }
+
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(
+            self.status(),
+            Status::Unavailable | Status::QuotaExceeded | Status::Internal
+        )
+    }
 }
 
 impl std::error::Error for FCMError {}
@@ -151,4 +309,3 @@ impl std::fmt::Display for FCMError {
         write!(f, "{}", self.inner.message)
     }
 }
-
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::challenge::Answer;
+
+pub(crate) const LISTENER_STOPPED: &str = "stopped";
+pub(crate) const LISTENER_LISTENING: &str = "listening";
+pub(crate) const LISTENER_FAILED: &str = "failed";
+
+/// Snapshot of the daemon state returned by `GetHealth`, timestamps are
+/// seconds since the unix epoch with 0 meaning never.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Type)]
+pub struct HealthReport {
+    pub listener_address: String,
+    pub listener_state: String,
+    pub bus_type: String,
+    pub fcm_token_valid: bool,
+    pub fcm_token_expiry: u64,
+    pub last_push: u64,
+    pub pending_requests: u32,
+    pub enrolled_devices: u32,
+    pub devices_last_seen: HashMap<u8, u64>,
+}
+
+/// State shared between the tcp listener and the dbus manager
+pub(crate) struct Health {
+    listener_address: Mutex<String>,
+    listener_state: Mutex<&'static str>,
+    bus_type: Mutex<&'static str>,
+    devices_last_seen: Mutex<HashMap<u8, u64>>,
+    // the device whose approval completed the current request
+    answered_by: Mutex<Option<u8>>,
+    // the device that denied or reported the current request
+    refused_by: Mutex<Option<(u8, Answer)>>,
+    // set while a request is in flight, devices can't answer otherwise
+    pending: Mutex<Option<Pending>>,
+}
+
+// the request in flight and the devices woken for it
+struct Pending {
+    user: String,
+    woken: Vec<u8>,
+}
+
+impl Health {
+    pub(crate) fn new(listener_address: String) -> Self {
+        Self {
+            listener_address: Mutex::new(listener_address),
+            listener_state: Mutex::new(LISTENER_STOPPED),
+            bus_type: Mutex::new("none"),
+            devices_last_seen: Mutex::new(HashMap::new()),
+            answered_by: Mutex::new(None),
+            refused_by: Mutex::new(None),
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn set_listener_state(&self, state: &'static str) {
+        *self.listener_state.lock().unwrap() = state;
+    }
+
+    pub(crate) fn set_bus_type(&self, bus_type: &'static str) {
+        *self.bus_type.lock().unwrap() = bus_type;
+    }
+
+    pub(crate) fn device_seen(&self, id: u8) {
+        self.devices_last_seen.lock().unwrap().insert(id, now());
+    }
+
+    pub(crate) fn set_answered_by(&self, id: Option<u8>) {
+        *self.answered_by.lock().unwrap() = id;
+    }
+
+    pub(crate) fn answered_by(&self) -> Option<u8> {
+        *self.answered_by.lock().unwrap()
+    }
+
+    pub(crate) fn set_refused_by(&self, refusal: Option<(u8, Answer)>) {
+        *self.refused_by.lock().unwrap() = refusal;
+    }
+
+    pub(crate) fn refused_by(&self) -> Option<(u8, Answer)> {
+        *self.refused_by.lock().unwrap()
+    }
+
+    pub(crate) fn begin_request(&self, user: &str) {
+        *self.pending.lock().unwrap() = Some(Pending {
+            user: user.into(),
+            woken: vec![],
+        });
+    }
+
+    pub(crate) fn end_request(&self) {
+        *self.pending.lock().unwrap() = None;
+    }
+
+    pub(crate) fn request_pending(&self) -> bool {
+        self.pending.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn device_woken(&self, id: u8) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.woken.push(id);
+        }
+    }
+
+    /// The user of the request in flight, if `id` was woken for it
+    pub(crate) fn woken_for(&self, id: u8) -> Option<String> {
+        self.pending
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|p| p.woken.contains(&id))
+            .map(|p| p.user.clone())
+    }
+
+    pub(crate) fn listener_address(&self) -> String {
+        self.listener_address.lock().unwrap().clone()
+    }
+
+    pub(crate) fn listener_state(&self) -> String {
+        self.listener_state.lock().unwrap().to_string()
+    }
+
+    pub(crate) fn bus_type(&self) -> String {
+        self.bus_type.lock().unwrap().to_string()
+    }
+
+    pub(crate) fn devices_last_seen(&self) -> HashMap<u8, u64> {
+        self.devices_last_seen.lock().unwrap().clone()
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+pub(crate) fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn ago(t: u64) -> String {
+    match t {
+        0 => "never".into(),
+        t => format!("{}s ago", now().saturating_sub(t)),
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "listener:   {} ({})",
+            self.listener_address, self.listener_state
+        )?;
+        writeln!(f, "bus:        {}", self.bus_type)?;
+        match (self.fcm_token_valid, self.fcm_token_expiry) {
+            (_, 0) => writeln!(f, "fcm token:  none")?,
+            (true, exp) => writeln!(
+                f,
+                "fcm token:  valid, expires in {}s",
+                exp.saturating_sub(now())
+            )?,
+            (false, exp) => writeln!(f, "fcm token:  expired {}", ago(exp))?,
+        }
+        writeln!(f, "last push:  {}", ago(self.last_push))?;
+        writeln!(f, "pending:    {}", self.pending_requests)?;
+        writeln!(f, "devices:    {} enrolled", self.enrolled_devices)?;
+
+        let mut seen: Vec<_> = self.devices_last_seen.iter().collect();
+        seen.sort();
+        for (id, t) in seen {
+            writeln!(f, "  {:<8} last seen {}", id, ago(*t))?;
+        }
+        Ok(())
+    }
+}
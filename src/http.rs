@@ -0,0 +1,38 @@
+use std::error::Error;
+use std::time::Duration;
+
+use crate::config::HttpConfig;
+
+pub(crate) const USER_AGENT: &str = concat!("except/", env!("CARGO_PKG_VERSION"));
+
+/// Starts a client from the http config, for callers that add to it
+pub(crate) fn builder(config: &HttpConfig) -> Result<reqwest::ClientBuilder, Box<dyn Error>> {
+    let mut builder = base_builder(config)?;
+    for path in &config.extra_roots {
+        let pem =
+            std::fs::read(path).map_err(|e| format!("failed to read root {:?}: {}", path, e))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid root {:?}: {}", path, e))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    Ok(builder)
+}
+
+/// Like [`builder`] but without the extra roots, for clients that pin
+/// their own
+pub(crate) fn base_builder(config: &HttpConfig) -> Result<reqwest::ClientBuilder, Box<dyn Error>> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .timeout(Duration::from_secs(config.timeout))
+        .user_agent(config.user_agent.as_deref().unwrap_or(USER_AGENT));
+    if let Some(proxy) = &config.proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).map_err(|e| format!("invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder)
+}
+
+pub(crate) fn client(config: &HttpConfig) -> Result<reqwest::Client, Box<dyn Error>> {
+    Ok(builder(config)?.build()?)
+}
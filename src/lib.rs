@@ -1,3 +1,6 @@
+mod config;
+pub use config::{BusType, Config, DEFAULT_CONFIG};
+
 mod except;
 pub use except::Except;
 
@@ -5,7 +8,23 @@ mod pam;
 pub use pam::pam_client;
 
 mod dbus;
-pub use dbus::ExceptManagerProxyBlocking;
+pub use dbus::{ExceptManagerProxy, ExceptManagerProxyBlocking};
+
+mod error;
+pub use error::ExceptError;
+
+mod health;
+pub use health::HealthReport;
+
+mod session;
+pub use session::Session;
 
-mod challenge;
 mod google;
+pub use google::{Credentials, FCMMessage, send_message, send_url};
+
+mod challenge;
+mod device;
+mod http;
+mod notifier;
+#[cfg(test)]
+mod stub;
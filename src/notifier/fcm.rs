@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use super::{Notifier, NotifyError, NotifyFuture, Push};
+use crate::config::FcmConfig;
+use crate::device::Device;
+use crate::google::{self, Credentials, FCMError, FCMMessage, Priority, Status, send_message};
+
+pub(crate) struct FcmNotifier {
+    client: reqwest::Client,
+    creds: Arc<Credentials>,
+    url: String,
+    validate_only: bool,
+}
+
+impl FcmNotifier {
+    pub(crate) fn new(
+        client: reqwest::Client,
+        creds: Arc<Credentials>,
+        config: &FcmConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let project_id = config
+            .project_id
+            .as_deref()
+            .or(creds.project_id())
+            .ok_or("no project_id in the credentials, set fcm.project_id")?;
+        let base_url = config.base_url.as_deref().unwrap_or(google::FCM_BASE_URL);
+        let url = google::send_url(base_url, project_id);
+        Ok(Self {
+            client,
+            creds,
+            url,
+            validate_only: config.validate_only,
+        })
+    }
+
+    async fn send(&self, device: &Device, push: &Push<'_>) -> Result<(), NotifyError> {
+        let device_token = device.fcm_token.as_deref().ok_or_else(|| {
+            NotifyError::NotConfigured(format!("no fcm token for: {}", device.name))
+        })?;
+        let sealed = push.seal(device)?;
+        let message = FCMMessage::builder(device_token)
+            .priority(Priority::High)
+            .ttl(push.ttl)
+            .collapse_key(push.collapse_key())
+            .data("kid", sealed.kid)
+            .data("nonce", sealed.nonce)
+            .data("payload", sealed.payload)
+            .validate_only(self.validate_only)
+            .build();
+        let token = self
+            .creds
+            .get_access_token()
+            .await
+            .map_err(|e| NotifyError::Failed(e.to_string()))?;
+        send_message(&self.client, &self.url, &token, &message)
+            .await
+            .map_err(|e| match e.downcast_ref::<FCMError>() {
+                Some(err) if err.status() == Status::Unregistered => {
+                    NotifyError::Gone(err.to_string())
+                }
+                _ => NotifyError::Failed(e.to_string()),
+            })
+    }
+}
+
+impl Notifier for FcmNotifier {
+    fn notify<'a>(&'a self, device: &'a Device, push: &'a Push<'a>) -> NotifyFuture<'a> {
+        Box::pin(self.send(device, push))
+    }
+}
@@ -0,0 +1,113 @@
+mod fcm;
+pub(crate) use fcm::FcmNotifier;
+mod unifiedpush;
+pub(crate) use unifiedpush::UnifiedPushNotifier;
+mod webpush;
+pub(crate) use webpush::{WebPushNotifier, WebPushSubscription};
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::device::{Device, Sealed};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NotifierKind {
+    #[default]
+    Fcm,
+    UnifiedPush,
+    WebPush,
+}
+
+impl FromStr for NotifierKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fcm" => Ok(NotifierKind::Fcm),
+            "unifiedpush" => Ok(NotifierKind::UnifiedPush),
+            "webpush" => Ok(NotifierKind::WebPush),
+            s => Err(format!("unknown notifier: {}", s)),
+        }
+    }
+}
+
+// prefix of the per request collapse key, short enough for a WebPush topic
+const COLLAPSE_KEY: &str = "except-auth";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PushKind {
+    // asks the device to connect back to the listener and run the challenge
+    WakeUp,
+    // the request reached a terminal state, the device drops its notification
+    Dismiss,
+}
+
+/// A data message for a device, the pushes of one request share a collapse
+/// key so a dismiss replaces its wake up while still queued, but never the
+/// wake up of a later request
+pub(crate) struct Push<'a> {
+    pub(crate) kind: PushKind,
+    // random per request, a dismiss only drops the notification of its own
+    pub(crate) request: u64,
+    pub(crate) id: u8,
+    pub(crate) hostname: &'a str,
+    // how long the push service should hold on to an undelivered message
+    pub(crate) ttl: Duration,
+}
+
+impl Push<'_> {
+    pub(crate) fn collapse_key(&self) -> String {
+        format!("{}-{:016x}", COLLAPSE_KEY, self.request)
+    }
+
+    pub(crate) fn payload(&self) -> serde_json::Value {
+        json!({
+            "type": self.kind,
+            "request": format!("{:016x}", self.request),
+            "id": self.id.to_string(),
+            "device": self.hostname,
+        })
+    }
+
+    // the push services only ever see the sealed form
+    pub(crate) fn seal(&self, device: &Device) -> Result<Sealed, NotifyError> {
+        device
+            .seal(self.payload().to_string().as_bytes())
+            .map_err(|e| NotifyError::Failed(format!("failed to seal push: {}", e)))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum NotifyError {
+    // the backend has no address for the device
+    NotConfigured(String),
+    // the backend reports the device's address as permanently gone
+    Gone(String),
+    Failed(String),
+}
+
+impl std::error::Error for NotifyError {}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotifyError::NotConfigured(s) => write!(f, "not configured: {}", s),
+            NotifyError::Gone(s) => write!(f, "gone: {}", s),
+            NotifyError::Failed(s) => write!(f, "failed: {}", s),
+        }
+    }
+}
+
+pub(crate) type NotifyFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), NotifyError>> + Send + 'a>>;
+
+pub(crate) trait Notifier: Send + Sync {
+    fn notify<'a>(&'a self, device: &'a Device, push: &'a Push<'a>) -> NotifyFuture<'a>;
+}
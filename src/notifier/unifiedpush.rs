@@ -0,0 +1,190 @@
+use std::error::Error;
+
+use reqwest::StatusCode;
+use tracing::debug;
+
+use super::{Notifier, NotifyError, NotifyFuture, Push};
+use crate::config::{HttpConfig, UnifiedPushConfig};
+use crate::device::Device;
+use crate::http;
+
+pub(crate) struct UnifiedPushNotifier {
+    client: reqwest::Client,
+    bearer_token: Option<String>,
+}
+
+impl UnifiedPushNotifier {
+    pub(crate) fn new(
+        client: reqwest::Client,
+        config: &UnifiedPushConfig,
+        http_config: &HttpConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let client = match &config.pinned_cert {
+            // the system and extra roots are dropped, so the pinned
+            // certificate gets a client of its own
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .map_err(|e| format!("failed to read pinned cert {:?}: {}", path, e))?;
+                http::base_builder(http_config)?
+                    .tls_built_in_root_certs(false)
+                    .add_root_certificate(reqwest::Certificate::from_pem(&pem)?)
+                    .build()?
+            }
+            None => client,
+        };
+        Ok(Self {
+            client,
+            bearer_token: config.bearer_token.clone(),
+        })
+    }
+
+    async fn send(&self, device: &Device, push: &Push<'_>) -> Result<(), NotifyError> {
+        let endpoint = device.unifiedpush_endpoint.as_deref().ok_or_else(|| {
+            NotifyError::NotConfigured(format!("no unifiedpush endpoint for: {}", device.name))
+        })?;
+
+        let sealed = push.seal(device)?;
+        debug!(endpoint, "sending unifiedpush message");
+        let mut req = self
+            .client
+            .post(endpoint)
+            .header("Urgency", "high")
+            .json(&sealed);
+        if let Some(token) = &self.bearer_token {
+            req = req.bearer_auth(token);
+        }
+
+        let res = req
+            .send()
+            .await
+            .map_err(|e| NotifyError::Failed(e.to_string()))?;
+        match res.status() {
+            s if s.is_success() => {
+                debug!(endpoint, "sent unifiedpush message successfully");
+                Ok(())
+            }
+            // the distributor dropped the registration, the app has to re-register
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(NotifyError::Gone(format!(
+                "unifiedpush endpoint returned {}",
+                res.status()
+            ))),
+            s => Err(NotifyError::Failed(format!(
+                "unifiedpush endpoint returned {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Notifier for UnifiedPushNotifier {
+    fn notify<'a>(&'a self, device: &'a Device, push: &'a Push<'a>) -> NotifyFuture<'a> {
+        Box::pin(self.send(device, push))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::device::Registry;
+    use crate::notifier::{NotifierKind, PushKind};
+    use crate::stub::{self, Stub};
+
+    fn device(name: &str, endpoint: &str) -> Device {
+        let dir = stub::temp_dir(name);
+        Registry::new(dir.join("devices.json"))
+            .enroll(
+                "phone",
+                "alice",
+                NotifierKind::UnifiedPush,
+                Some(endpoint.into()),
+            )
+            .unwrap()
+            .clone()
+    }
+
+    fn push(device: &Device) -> Push<'static> {
+        Push {
+            kind: PushKind::WakeUp,
+            request: 1,
+            id: device.id,
+            hostname: "test",
+            ttl: Duration::from_secs(30),
+        }
+    }
+
+    fn pinned(cert: &str) -> UnifiedPushNotifier {
+        let config = UnifiedPushConfig {
+            pinned_cert: Some(
+                Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures")
+                    .join(cert),
+            ),
+            ..Default::default()
+        };
+        UnifiedPushNotifier::new(reqwest::Client::new(), &config, &HttpConfig::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bearer_token_is_sent() {
+        let distributor = Stub::start(|_| (201, json!({}))).await;
+        let config = UnifiedPushConfig {
+            bearer_token: Some("ntfy-token".into()),
+            ..Default::default()
+        };
+        let notifier =
+            UnifiedPushNotifier::new(reqwest::Client::new(), &config, &HttpConfig::default())
+                .unwrap();
+        let device = device("up-bearer", &format!("{}/up/abc", distributor.url));
+
+        notifier.send(&device, &push(&device)).await.unwrap();
+        let requests = distributor.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/up/abc");
+        assert_eq!(
+            requests[0].header("Authorization"),
+            Some("Bearer ntfy-token")
+        );
+        assert_eq!(requests[0].header("Urgency"), Some("high"));
+        assert!(requests[0].json()["payload"].is_string());
+    }
+
+    #[tokio::test]
+    async fn dropped_registration_is_gone() {
+        let distributor = Stub::start(|_| (404, json!({}))).await;
+        let notifier = UnifiedPushNotifier::new(
+            reqwest::Client::new(),
+            &UnifiedPushConfig::default(),
+            &HttpConfig::default(),
+        )
+        .unwrap();
+        let device = device("up-gone", &format!("{}/up/abc", distributor.url));
+
+        let err = notifier.send(&device, &push(&device)).await.unwrap_err();
+        assert!(matches!(err, NotifyError::Gone(_)), "{:?}", err);
+        assert_eq!(distributor.requests()[0].header("Authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn only_the_pinned_cert_is_trusted() {
+        let distributor =
+            Stub::start_tls("pinned_cert.pem", "pinned_key.pem", |_| (201, json!({}))).await;
+        let device = device("up-pinned", &format!("{}/up/abc", distributor.url));
+
+        pinned("pinned_cert.pem")
+            .send(&device, &push(&device))
+            .await
+            .unwrap();
+        let err = pinned("other_cert.pem")
+            .send(&device, &push(&device))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NotifyError::Failed(_)), "{:?}", err);
+        assert_eq!(distributor.requests().len(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+use std::error::Error;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use reqwest::StatusCode;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{aead, agreement, hkdf, signature};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+
+use super::{Notifier, NotifyError, NotifyFuture, Push};
+use crate::config::WebPushConfig;
+use crate::device::Device;
+use crate::health;
+
+// record size advertised in the aes128gcm header, our payloads fit in one
+const RECORD_SIZE: u32 = 4096;
+const VAPID_EXPIRY: Duration = Duration::from_secs(12 * 3600);
+
+/// A browser PushSubscription as returned by `subscription.toJSON()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WebPushSubscription {
+    pub(crate) endpoint: String,
+    pub(crate) keys: WebPushKeys,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WebPushKeys {
+    pub(crate) p256dh: String,
+    pub(crate) auth: String,
+}
+
+pub(crate) struct WebPushNotifier {
+    client: reqwest::Client,
+    vapid_key: signature::EcdsaKeyPair,
+    vapid_public_key: String,
+    subject: String,
+    rng: SystemRandom,
+}
+
+impl WebPushNotifier {
+    pub(crate) fn new(
+        client: reqwest::Client,
+        config: &WebPushConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let subject = config
+            .subject
+            .clone()
+            .ok_or("no vapid subject configured")?;
+        let public_key = config
+            .public_key
+            .as_deref()
+            .ok_or("no vapid public key configured")?;
+        let private_key_file = config
+            .private_key_file
+            .as_ref()
+            .ok_or("no vapid private key configured")?;
+        let private_key = std::fs::read_to_string(private_key_file)
+            .map_err(|e| format!("failed to read {:?}: {}", private_key_file, e))?;
+
+        let rng = SystemRandom::new();
+        let vapid_key = signature::EcdsaKeyPair::from_private_key_and_public_key(
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            &BASE64URL.decode(private_key.trim())?,
+            &BASE64URL.decode(public_key)?,
+            &rng,
+        )
+        .map_err(|e| format!("invalid vapid key pair: {}", e))?;
+
+        Ok(Self {
+            client,
+            vapid_key,
+            vapid_public_key: public_key.into(),
+            subject,
+            rng,
+        })
+    }
+
+    // RFC 8292, the audience is the origin of the push service
+    fn vapid_jwt(&self, endpoint: &reqwest::Url) -> Result<String, Box<dyn Error>> {
+        let header = BASE64URL.encode(json!({"typ": "JWT", "alg": "ES256"}).to_string());
+        let claims = BASE64URL.encode(
+            json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": health::now() + VAPID_EXPIRY.as_secs(),
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let signing_input = format!("{}.{}", header, claims);
+        let sig = self
+            .vapid_key
+            .sign(&self.rng, signing_input.as_bytes())
+            .map_err(|_| "failed to sign vapid jwt")?;
+        Ok(format!("{}.{}", signing_input, BASE64URL.encode(sig)))
+    }
+
+    // RFC 8291 message encryption using the aes128gcm content coding of RFC 8188
+    fn encrypt(&self, keys: &WebPushKeys, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let as_private = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &self.rng)
+            .map_err(|_| "failed to generate ephemeral key")?;
+        let mut salt = [0; 16];
+        self.rng
+            .fill(&mut salt)
+            .map_err(|_| "failed to generate salt")?;
+        encrypt(keys, plaintext, salt, as_private)
+    }
+
+    async fn send(&self, device: &Device, push: &Push<'_>) -> Result<(), NotifyError> {
+        let subscription = device.webpush.as_ref().ok_or_else(|| {
+            NotifyError::NotConfigured(format!("no web push subscription for: {}", device.name))
+        })?;
+        let endpoint = reqwest::Url::parse(&subscription.endpoint)
+            .map_err(|e| NotifyError::NotConfigured(e.to_string()))?;
+
+        // already end to end encrypted to the subscription keys by RFC 8291,
+        // browser authenticators don't hold the device key
+        let payload = push.payload();
+        let body = self
+            .encrypt(&subscription.keys, payload.to_string().as_bytes())
+            .map_err(|e| NotifyError::Failed(e.to_string()))?;
+        let jwt = self
+            .vapid_jwt(&endpoint)
+            .map_err(|e| NotifyError::Failed(e.to_string()))?;
+
+        debug!(endpoint = endpoint.as_str(), "sending web push message");
+        let res = self
+            .client
+            .post(endpoint)
+            .header("TTL", push.ttl.as_secs())
+            .header("Urgency", "high")
+            // RFC 8030 topic, a newer message replaces one still queued
+            .header("Topic", push.collapse_key())
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header(
+                "Authorization",
+                format!("vapid t={}, k={}", jwt, self.vapid_public_key),
+            )
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| NotifyError::Failed(e.to_string()))?;
+
+        match res.status() {
+            s if s.is_success() => {
+                debug!("sent web push message successfully");
+                Ok(())
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(NotifyError::Gone(format!(
+                "web push subscription returned {}",
+                res.status()
+            ))),
+            s => Err(NotifyError::Failed(format!(
+                "web push endpoint returned {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Notifier for WebPushNotifier {
+    fn notify<'a>(&'a self, device: &'a Device, push: &'a Push<'a>) -> NotifyFuture<'a> {
+        Box::pin(self.send(device, push))
+    }
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn expand<const N: usize>(prk: &hkdf::Prk, info: &[&[u8]]) -> Result<[u8; N], Box<dyn Error>> {
+    let mut out = [0; N];
+    prk.expand(info, Len(N))
+        .and_then(|okm| okm.fill(&mut out))
+        .map_err(|_| "hkdf expand failed")?;
+    Ok(out)
+}
+
+// the salt and ephemeral key are parameters so the RFC 8291 example can be replayed
+fn encrypt(
+    keys: &WebPushKeys,
+    plaintext: &[u8],
+    salt: [u8; 16],
+    as_private: agreement::EphemeralPrivateKey,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let ua_public = BASE64URL.decode(&keys.p256dh)?;
+    let auth_secret = BASE64URL.decode(&keys.auth)?;
+
+    let as_public = as_private
+        .compute_public_key()
+        .map_err(|_| "failed to compute ephemeral public key")?;
+    let ikm = agreement::agree_ephemeral(
+        as_private,
+        &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, &ua_public),
+        |ecdh_secret| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &auth_secret).extract(ecdh_secret);
+            let info = [b"WebPush: info\0", &ua_public[..], as_public.as_ref()];
+            expand::<32>(&prk, &info)
+        },
+    )
+    .map_err(|_| "invalid subscription public key")??;
+
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(&ikm);
+    let cek = expand::<16>(&prk, &[b"Content-Encoding: aes128gcm\0"])?;
+    let nonce = expand::<12>(&prk, &[b"Content-Encoding: nonce\0"])?;
+
+    // a single record, terminated by the last record delimiter
+    let mut record = plaintext.to_vec();
+    record.push(2);
+    let key = aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::AES_128_GCM, &cek).map_err(|_| "invalid cek")?,
+    );
+    key.seal_in_place_append_tag(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut record,
+    )
+    .map_err(|_| "failed to encrypt payload")?;
+
+    let mut body = salt.to_vec();
+    body.extend(RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_ref().len() as u8);
+    body.extend(as_public.as_ref());
+    body.extend(record);
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::test::rand::FixedSliceRandom;
+
+    use super::*;
+
+    // RFC 8291 Appendix A
+    #[test]
+    fn encrypts_the_rfc_example() {
+        let keys = WebPushKeys {
+            p256dh: "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4".into(),
+            auth: "BTBZMqHH6r4Tts7J_aSIgg".into(),
+        };
+        let as_private_bytes = BASE64URL
+            .decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")
+            .unwrap();
+        let as_private = agreement::EphemeralPrivateKey::generate(
+            &agreement::ECDH_P256,
+            &FixedSliceRandom {
+                bytes: &as_private_bytes,
+            },
+        )
+        .unwrap();
+        let salt = BASE64URL.decode("DGv6ra1nlYgDCS1FRnbzlw").unwrap();
+
+        let body = encrypt(
+            &keys,
+            b"When I grow up, I want to be a watermelon",
+            salt.try_into().unwrap(),
+            as_private,
+        )
+        .unwrap();
+        assert_eq!(
+            BASE64URL.encode(body),
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::Type;
+
+use crate::health;
+
+/// A login opened through the pam session hooks, `device` is the device that
+/// approved it or 0 when no device was involved
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct Session {
+    pub id: u32,
+    pub user: String,
+    pub service: String,
+    pub tty: String,
+    pub rhost: String,
+    pub device: u8,
+    pub started: u64,
+}
+
+/// Sessions that are open right now, they don't survive a daemon restart
+#[derive(Default)]
+pub(crate) struct Sessions {
+    next_id: u32,
+    active: HashMap<u32, Session>,
+}
+
+impl Sessions {
+    pub(crate) fn open(
+        &mut self,
+        user: String,
+        service: String,
+        tty: String,
+        rhost: String,
+        device: u8,
+    ) -> u32 {
+        // 0 is never handed out
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let id = self.next_id;
+        self.active.insert(
+            id,
+            Session {
+                id,
+                user,
+                service,
+                tty,
+                rhost,
+                device,
+                started: health::now(),
+            },
+        );
+        id
+    }
+
+    pub(crate) fn get(&self, id: u32) -> Option<&Session> {
+        self.active.get(&id)
+    }
+
+    pub(crate) fn close(&mut self, id: u32) -> Option<Session> {
+        self.active.remove(&id)
+    }
+
+    pub(crate) fn of_user(&self, user: &str) -> Vec<Session> {
+        let mut sessions: Vec<_> = self
+            .active
+            .values()
+            .filter(|s| s.user == user)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.started);
+        sessions
+    }
+}
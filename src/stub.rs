@@ -0,0 +1,220 @@
+//! A local HTTP listener for tests, every request is recorded and answered
+//! with whatever the handler returns for it. `start_tls` serves the same over
+//! TLS with a certificate from the fixtures.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_native_tls::{TlsAcceptor, native_tls};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    // with the query string
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+
+    /// A field of an urlencoded form body
+    pub(crate) fn form(&self, key: &str) -> Option<String> {
+        let url = reqwest::Url::parse(&format!("http://stub/?{}", self.body)).ok()?;
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    }
+}
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+type Handler = dyn Fn(&Request) -> (u16, Value) + Send + Sync;
+
+pub(crate) struct Stub {
+    pub(crate) url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    task: JoinHandle<()>,
+}
+
+impl Stub {
+    pub(crate) async fn start(
+        handler: impl Fn(&Request) -> (u16, Value) + Send + Sync + 'static,
+    ) -> Self {
+        Self::listen(None, handler).await
+    }
+
+    /// Serves https with the fixture certificate `cert` and its key `key`
+    pub(crate) async fn start_tls(
+        cert: &str,
+        key: &str,
+        handler: impl Fn(&Request) -> (u16, Value) + Send + Sync + 'static,
+    ) -> Self {
+        let cert = std::fs::read(Path::new(FIXTURES).join(cert)).unwrap();
+        let key = std::fs::read(Path::new(FIXTURES).join(key)).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        Self::listen(Some(acceptor.into()), handler).await
+    }
+
+    async fn listen(
+        acceptor: Option<TlsAcceptor>,
+        handler: impl Fn(&Request) -> (u16, Value) + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let scheme = if acceptor.is_some() { "https" } else { "http" };
+        let url = format!("{}://{}", scheme, listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (handler, recorded) = (handler.clone(), recorded.clone());
+                match acceptor.clone() {
+                    Some(acceptor) => tokio::spawn(async move {
+                        // a client that doesn't trust the certificate hangs up here
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            serve(stream, handler, recorded).await;
+                        }
+                    }),
+                    None => tokio::spawn(serve(stream, handler, recorded)),
+                };
+            }
+        });
+        Self {
+            url,
+            requests,
+            task,
+        }
+    }
+
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Stub {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<Request>>>,
+) {
+    let Some(req) = read_request(&mut stream).await else {
+        return;
+    };
+    let (status, body) = handler(&req);
+    requests.lock().unwrap().push(req);
+    let body = body.to_string();
+    let res = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(res.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Option<Request> {
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    let end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+    };
+
+    let head = String::from_utf8(buf[..end].to_vec()).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+        .collect();
+    let length: usize = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+
+    let mut body = buf[end + 4..].to_vec();
+    while body.len() < length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        body.extend(&chunk[..n]);
+    }
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// A fresh directory for the files of one test
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("except-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A service account key in `dir` whose grants go to `token_uri`
+pub(crate) fn service_account(dir: &Path, token_uri: &str) -> PathBuf {
+    let private_key = std::fs::read_to_string(Path::new(FIXTURES).join("mock_key.pem")).unwrap();
+    let account = json!({
+        "type": "service_account",
+        "project_id": "except-test",
+        "private_key_id": "stub",
+        "private_key": private_key,
+        "client_email": "except@except-test.iam.gserviceaccount.com",
+        "client_id": "0",
+        "auth_uri": "http://stub/auth",
+        "token_uri": token_uri,
+        "auth_provider_x509_cert_url": "http://stub/certs",
+        "client_x509_cert_url": "http://stub/certs/except",
+        "universe_domain": "googleapis.com",
+    });
+    let path = dir.join("service_account.json");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .unwrap()
+        .write_all(account.to_string().as_bytes())
+        .unwrap();
+    path
+}
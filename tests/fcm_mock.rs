@@ -0,0 +1,209 @@
+//! Drives the fcm client against the mock_fcm binary, covering the OAuth
+//! token grant, retries of transient errors and UNREGISTERED tokens.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use except::{Credentials, FCMMessage, send_message, send_url};
+use serde_json::{Value, json};
+
+const PROJECT_ID: &str = "except-test";
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+struct Mock {
+    child: Child,
+    base_url: String,
+    dir: PathBuf,
+}
+
+impl Mock {
+    fn start(name: &str) -> Self {
+        // the port is only reserved long enough to learn a free one
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_mock_fcm"))
+            .arg(address.to_string())
+            .arg(Path::new(FIXTURES).join("mock_key.pub.pem"))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start mock_fcm");
+
+        let started = Instant::now();
+        while std::net::TcpStream::connect(address).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "mock_fcm did not start listening"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let dir = std::env::temp_dir().join(format!("except-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self {
+            child,
+            base_url: format!("http://{}", address),
+            dir,
+        }
+    }
+
+    // a service account key whose token_uri points at the mock
+    fn credentials(&self, key: &str) -> Arc<Credentials> {
+        let private_key = std::fs::read_to_string(Path::new(FIXTURES).join(key)).unwrap();
+        let account = json!({
+            "type": "service_account",
+            "project_id": PROJECT_ID,
+            "private_key_id": "mock",
+            "private_key": private_key,
+            "client_email": format!("except@{}.iam.gserviceaccount.com", PROJECT_ID),
+            "client_id": "0",
+            "auth_uri": format!("{}/auth", self.base_url),
+            "token_uri": format!("{}/token", self.base_url),
+            "auth_provider_x509_cert_url": format!("{}/certs", self.base_url),
+            "client_x509_cert_url": format!("{}/certs/except", self.base_url),
+            "universe_domain": "googleapis.com",
+        });
+        let path = self.dir.join(key).with_extension("json");
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .unwrap()
+            .write_all(account.to_string().as_bytes())
+            .unwrap();
+        Arc::new(Credentials::from_file(&path, reqwest::Client::new()).unwrap())
+    }
+
+    fn send_url(&self) -> String {
+        send_url(&self.base_url, PROJECT_ID)
+    }
+
+    async fn queue_errors(&self, errors: Value) {
+        let res = reqwest::Client::new()
+            .post(format!("{}/mock/errors", self.base_url))
+            .json(&errors)
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+    }
+
+    async fn messages(&self) -> Vec<Value> {
+        reqwest::get(format!("{}/mock/messages", self.base_url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn message() -> FCMMessage {
+    FCMMessage::builder("device-token")
+        .data("type", "auth")
+        .build()
+}
+
+#[tokio::test]
+async fn token_grant_is_cached() {
+    let mock = Mock::start("token");
+    let creds = mock.credentials("mock_key.pem");
+
+    let token = creds.get_access_token().await.unwrap();
+    assert_eq!(token, "mock-token-1");
+    assert!(creds.token_valid());
+    // a second call must not go back to the token endpoint
+    assert_eq!(creds.get_access_token().await.unwrap(), token);
+
+    let client = reqwest::Client::new();
+    send_message(&client, &mock.send_url(), &token, &message())
+        .await
+        .unwrap();
+    assert_eq!(mock.messages().await.len(), 1);
+}
+
+#[tokio::test]
+async fn token_grant_with_an_untrusted_key_fails() {
+    let mock = Mock::start("untrusted");
+    let creds = mock.credentials("untrusted_key.pem");
+
+    let err = creds.get_access_token().await.unwrap_err();
+    assert!(err.to_string().contains("invalid_grant"), "{}", err);
+    assert!(!creds.token_valid());
+}
+
+#[tokio::test]
+async fn transient_errors_are_retried() {
+    let mock = Mock::start("retry");
+    let token = mock
+        .credentials("mock_key.pem")
+        .get_access_token()
+        .await
+        .unwrap();
+    mock.queue_errors(json!([
+        {"status": "UNAVAILABLE"},
+        {"status": "QUOTA_EXCEEDED", "retry_after": 0},
+    ]))
+    .await;
+
+    let client = reqwest::Client::new();
+    send_message(&client, &mock.send_url(), &token, &message())
+        .await
+        .unwrap();
+    assert_eq!(mock.messages().await.len(), 1);
+}
+
+#[tokio::test]
+async fn long_retry_after_is_not_waited_for() {
+    let mock = Mock::start("retry-after");
+    let token = mock
+        .credentials("mock_key.pem")
+        .get_access_token()
+        .await
+        .unwrap();
+    mock.queue_errors(json!({"status": "QUOTA_EXCEEDED", "retry_after": 3600}))
+        .await;
+
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+    send_message(&client, &mock.send_url(), &token, &message())
+        .await
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(mock.messages().await.is_empty());
+}
+
+#[tokio::test]
+async fn unregistered_is_not_retried() {
+    let mock = Mock::start("unregistered");
+    let token = mock
+        .credentials("mock_key.pem")
+        .get_access_token()
+        .await
+        .unwrap();
+    // a retry would get through, the queue only holds one error
+    mock.queue_errors(json!({"status": "UNREGISTERED"})).await;
+
+    let client = reqwest::Client::new();
+    let err = send_message(&client, &mock.send_url(), &token, &message())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "UNREGISTERED");
+    assert!(mock.messages().await.is_empty());
+}